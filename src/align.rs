@@ -0,0 +1,551 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbImage, Rgba, RgbaImage};
+use strum_macros::{Display, EnumString, VariantNames};
+
+use crate::faces::Face;
+use crate::geom::{WHf, WHi, XYWHi, XYf, XYi, fit_inside, intersect, whf_to_whi, xyf_to_xyi};
+
+/**
+ * Find the positions of the two eyes of a face, sorted from left to right in the image.
+ * The eyes are expected to be the first two landmarks, as in BlazeFace.
+ */
+pub fn eye_positions(face: &Face) -> Option<(XYf, XYf)> {
+	match face.landmarks.as_slice() {
+		[a, b, ..] if a.0 <= b.0 => Some((*a, *b)),
+		[a, b, ..] => Some((*b, *a)),
+		_ => None,
+	}
+}
+
+/**
+ * Find the angle (in radians) of the line between the eyes of a face; positive when the right eye is lower
+ */
+pub fn eye_angle(face: &Face) -> Option<f32> {
+	eye_positions(face).map(|(left, right)| (right.1 - left.1).atan2(right.0 - left.0))
+}
+
+/// Filter used when resampling images, from fastest to highest quality.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum ResampleFilter {
+	/// Nearest neighbor; blocky, but fastest.
+	Nearest,
+	/// Linear interpolation.
+	Triangle,
+	/// Cubic interpolation (Catmull-Rom spline).
+	CatmullRom,
+	/// Gaussian blur; soft, without ringing.
+	Gaussian,
+	/// Lanczos with a window of 3; sharpest, but slowest.
+	Lanczos3,
+}
+
+impl ResampleFilter {
+	/// The equivalent filter for `imageops::resize`.
+	pub fn filter_type(&self) -> FilterType {
+		match self {
+			ResampleFilter::Nearest => FilterType::Nearest,
+			ResampleFilter::Triangle => FilterType::Triangle,
+			ResampleFilter::CatmullRom => FilterType::CatmullRom,
+			ResampleFilter::Gaussian => FilterType::Gaussian,
+			ResampleFilter::Lanczos3 => FilterType::Lanczos3,
+		}
+	}
+
+	/// How far (in pixels) the filter reaches, at a 1:1 scale.
+	fn support(&self) -> f32 {
+		match self {
+			ResampleFilter::Nearest => 0.5,
+			ResampleFilter::Triangle => 1.0,
+			ResampleFilter::CatmullRom => 2.0,
+			ResampleFilter::Gaussian => 3.0,
+			ResampleFilter::Lanczos3 => 3.0,
+		}
+	}
+
+	/// Weight of a pixel at a given distance; the same kernels used by `imageops::resize`.
+	fn kernel(&self, x: f32) -> f32 {
+		let x = x.abs();
+		match self {
+			ResampleFilter::Nearest if x <= 0.5 => 1.0,
+			ResampleFilter::Triangle if x < 1.0 => 1.0 - x,
+			ResampleFilter::CatmullRom if x < 1.0 => 1.5 * x.powi(3) - 2.5 * x.powi(2) + 1.0,
+			ResampleFilter::CatmullRom if x < 2.0 => -0.5 * x.powi(3) + 2.5 * x.powi(2) - 4.0 * x + 2.0,
+			ResampleFilter::Gaussian => (-2.0 * x.powi(2)).exp(),
+			ResampleFilter::Lanczos3 if x < 3.0 => sinc(x) * sinc(x / 3.0),
+			_ => 0.0,
+		}
+	}
+}
+
+fn sinc(x: f32) -> f32 {
+	if x == 0.0 {
+		return 1.0;
+	}
+	let x = x * std::f32::consts::PI;
+	x.sin() / x
+}
+
+/// Most pixels a filter reaches in each direction, at the largest stretch of `sample()`.
+const MAX_TAPS: usize = 64;
+
+/**
+ * Sample a pixel with the filter's kernel, stretched by `stretch` (at least 1) when downscaling so all the source
+ * pixels under the cell pixel are weighed, like `imageops::resize` does. Like it, only pixels inside the image
+ * are weighed.
+ */
+fn sample(image: &RgbImage, x: f32, y: f32, filter: ResampleFilter, stretch: f32) -> [u8; 3] {
+	let (width, height) = image.dimensions();
+	let nearest = || {
+		let pixel_x = x.round().clamp(0.0, (width - 1) as f32) as u32;
+		let pixel_y = y.round().clamp(0.0, (height - 1) as f32) as u32;
+		image.get_pixel(pixel_x, pixel_y).0
+	};
+	if filter == ResampleFilter::Nearest {
+		return nearest();
+	}
+
+	// The weights of each column and row, as the kernel is the same for all of them
+	let support = filter.support() * stretch;
+	let weights = |center: f32, size: u32| {
+		let first = ((center - support).ceil() as i32).max(0);
+		let last = ((center + support).floor() as i32).min(size as i32 - 1);
+		let mut weights = [0f32; MAX_TAPS];
+		let num_taps = (last - first + 1).clamp(0, MAX_TAPS as i32) as usize;
+		for (index, weight) in weights.iter_mut().take(num_taps).enumerate() {
+			*weight = filter.kernel(((first + index as i32) as f32 - center) / stretch);
+		}
+		(first, num_taps, weights)
+	};
+	let (first_x, num_taps_x, weights_x) = weights(x, width);
+	let (first_y, num_taps_y, weights_y) = weights(y, height);
+
+	let mut sum = [0f32; 3];
+	let mut total_weight = 0f32;
+	for (index_y, &weight_y) in weights_y.iter().take(num_taps_y).enumerate() {
+		let pixel_y = first_y as u32 + index_y as u32;
+		for (index_x, &weight_x) in weights_x.iter().take(num_taps_x).enumerate() {
+			let weight = weight_x * weight_y;
+			if weight == 0.0 {
+				continue;
+			}
+			let pixel_x = first_x as u32 + index_x as u32;
+			let pixel = image.get_pixel(pixel_x, pixel_y).0;
+			for c in 0..3 {
+				sum[c] += pixel[c] as f32 * weight;
+			}
+			total_weight += weight;
+		}
+	}
+	// Just outside the edges, the filter may not reach any pixel
+	if total_weight == 0.0 {
+		return nearest();
+	}
+	sum.map(|value| (value / total_weight).round().clamp(0.0, 255.0) as u8)
+}
+
+/**
+ * Render the part of an image that lands inside a cell in a single pass from the source image: each cell pixel
+ * is mapped back through the transform and sampled with the filter. When downscaling, the filter is stretched
+ * (up to 8 times, to bound the cost) so small faces don't alias. Areas outside of the source image are
+ * transparent, with anti-aliased edges except for the nearest filter.
+ */
+fn transform_into_cell(
+	image: &RgbImage,
+	transform: &FaceTransform,
+	cell_size: WHi,
+	filter: ResampleFilter,
+) -> RgbaImage {
+	let (width, height) = image.dimensions();
+	let (sin, cos) = transform.angle.sin_cos();
+	let stretch = (1.0 / transform.scale).clamp(1.0, 8.0);
+	RgbaImage::from_fn(cell_size.0, cell_size.1, |cell_x, cell_y| {
+		// Position in the source image, from the center of the cell pixel
+		let dx = cell_x as f32 + 0.5 - transform.cell_anchor.0;
+		let dy = cell_y as f32 + 0.5 - transform.cell_anchor.1;
+		let x = transform.source_anchor.0 + (dx * cos - dy * sin) / transform.scale - 0.5;
+		let y = transform.source_anchor.1 + (dx * sin + dy * cos) / transform.scale - 0.5;
+
+		// Coverage from the distance to the nearest edge of the source image, in cell pixels
+		let edge_distance =
+			(x + 0.5).min(y + 0.5).min(width as f32 - 0.5 - x).min(height as f32 - 0.5 - y) * transform.scale;
+		let alpha = match filter {
+			ResampleFilter::Nearest if edge_distance >= 0.0 => 255,
+			ResampleFilter::Nearest => 0,
+			_ => ((edge_distance + 0.5).clamp(0.0, 1.0) * 255.0).round() as u8,
+		};
+		if alpha == 0 {
+			return Rgba([0, 0, 0, 0]);
+		}
+		let [r, g, b] = sample(image, x, y, filter, stretch);
+		Rgba([r, g, b, alpha])
+	})
+}
+
+/// How a source image is placed inside a cell: scaled, then moved so its anchor lands on the cell anchor,
+/// then rotated around it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaceTransform {
+	/// Scale applied to the source image.
+	pub scale: f32,
+	/// Point in the source image that is used to position the face (e.g. the center of the face).
+	pub source_anchor: XYf,
+	/// Where the source anchor lands inside the cell.
+	pub cell_anchor: XYf,
+	/// Rotation around the anchor, in radians; the source is rotated by -angle.
+	pub angle: f32,
+}
+
+impl FaceTransform {
+	/// Where the top left corner of the scaled source image lands inside the cell, before rotating.
+	pub fn offset(&self) -> XYf {
+		(
+			self.cell_anchor.0 - self.source_anchor.0 * self.scale,
+			self.cell_anchor.1 - self.source_anchor.1 * self.scale,
+		)
+	}
+}
+
+/// How faces are aligned inside each cell.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum AlignMode {
+	/// Center the face rectangle in the cell, scaling it to fit the typical face size.
+	Box,
+	/// Map the eyes to fixed points in the cell. Faces without eye landmarks are aligned by their box.
+	Eyes,
+}
+
+/**
+ * Align a face by centering its bounding box in the cell, fitting it inside the target faces rectangle
+ */
+pub fn box_transform(face: &Face, target_faces_rect: WHf, cell_size: WHi) -> FaceTransform {
+	let (face_x, face_y, face_width, face_height) = face.rect;
+
+	// Find out what the face size should be inside our face target box
+	let target_face_rect: WHf = fit_inside(target_faces_rect, (face_width, face_height));
+	FaceTransform {
+		scale: target_face_rect.0 / face_width,
+		source_anchor: (face_x + face_width / 2.0, face_y + face_height / 2.0),
+		cell_anchor: (cell_size.0 as f32 / 2.0, cell_size.1 as f32 / 2.0),
+		angle: 0.0,
+	}
+}
+
+/**
+ * Align a face by its eyes: the point between the eyes lands horizontally centered in the cell, at
+ * `eye_height` (a fraction of the cell height), with the eyes `eye_distance` (a fraction of the cell width) apart
+ */
+pub fn eyes_transform(
+	face: &Face,
+	eye_distance: f32,
+	eye_height: f32,
+	cell_size: WHi,
+) -> Option<FaceTransform> {
+	let (left, right) = eye_positions(face)?;
+	let source_distance = ((right.0 - left.0).powi(2) + (right.1 - left.1).powi(2)).sqrt();
+	if source_distance == 0.0 {
+		return None;
+	}
+
+	Some(FaceTransform {
+		scale: eye_distance * cell_size.0 as f32 / source_distance,
+		source_anchor: ((left.0 + right.0) / 2.0, (left.1 + right.1) / 2.0),
+		cell_anchor: (cell_size.0 as f32 / 2.0, eye_height * cell_size.1 as f32),
+		angle: 0.0,
+	})
+}
+
+/**
+ * Find the window of the source image (x, y, width, height) that lands inside the cell once transformed,
+ * including a margin for the resampling filter; returns None if no part of the image is visible
+ */
+fn source_window(image_size: WHi, transform: &FaceTransform, cell_size: WHi) -> Option<XYWHi> {
+	let scale = transform.scale;
+	if !(scale.is_finite() && scale > 0.0) {
+		return None;
+	}
+	let center = (transform.source_anchor.0 * scale, transform.source_anchor.1 * scale);
+	let (sin, cos) = transform.angle.sin_cos();
+
+	// Map the cell corners back to the source image; with rotation, the window is their bounding box
+	let (mut x1, mut y1, mut x2, mut y2) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+	for (cell_x, cell_y) in [(0, 0), (cell_size.0, 0), (0, cell_size.1), (cell_size.0, cell_size.1)] {
+		let dx = cell_x as f32 - transform.cell_anchor.0;
+		let dy = cell_y as f32 - transform.cell_anchor.1;
+		let x = (center.0 + dx * cos - dy * sin) / scale;
+		let y = (center.1 + dx * sin + dy * cos) / scale;
+		x1 = x1.min(x);
+		y1 = y1.min(y);
+		x2 = x2.max(x);
+		y2 = y2.max(y);
+	}
+
+	// Keep the pixels the filter reaches, so resizing sees the same neighbors as it would for the whole image:
+	// up to 3 pixels for Lanczos3, plus one for rounding; when downscaling, the filter reaches further
+	let margin = 4.0 * (1.0 / scale).max(1.0);
+	let (left, top) = ((x1 - margin).floor(), (y1 - margin).floor());
+	let right = left + (x2 - x1 + margin * 2.0).ceil() + 1.0;
+	let bottom = top + (y2 - y1 + margin * 2.0).ceil() + 1.0;
+
+	// Clipped to the image before converting, as tiny scales make windows too large for pixel coordinates
+	let (left, top) = (left.max(0.0), top.max(0.0));
+	let (right, bottom) = (right.min(image_size.0 as f32), bottom.min(image_size.1 as f32));
+	(right > left && bottom > top).then_some((
+		left as i32,
+		top as i32,
+		(right - left) as u32,
+		(bottom - top) as u32,
+	))
+}
+
+/**
+ * Whether any part of an image of the given size is visible in the cell once transformed, without rendering it
+ */
+pub fn is_visible(image_size: WHi, transform: &FaceTransform, cell_size: WHi) -> bool {
+	source_window(image_size, transform, cell_size).is_some()
+}
+
+/**
+ * Render a face into a cell-ready image, returning it with its offset inside the cell.
+ * Only the part of the image that is visible in the cell is resampled and kept; returns None if nothing is
+ * visible.
+ */
+pub fn render_face(
+	image: &RgbImage,
+	transform: &FaceTransform,
+	cell_size: WHi,
+	filter: ResampleFilter,
+) -> Option<(RgbaImage, XYi)> {
+	let scale = transform.scale;
+	let (window_x, window_y, window_width, window_height) =
+		source_window(image.dimensions(), transform, cell_size)?;
+
+	// Rotated faces are sampled straight from the source, so they are only resampled once
+	if transform.angle != 0.0 {
+		return Some((transform_into_cell(image, transform, cell_size, filter), (0, 0)));
+	}
+
+	// Scale the visible window appropriately
+	let window_image =
+		imageops::crop_imm(image, window_x as u32, window_y as u32, window_width, window_height);
+	let new_window_size: WHi = whf_to_whi((window_width as f32 * scale, window_height as f32 * scale));
+	let resized_image = imageops::resize(
+		&*window_image,
+		new_window_size.0.max(1),
+		new_window_size.1.max(1),
+		filter.filter_type(),
+	);
+
+	// Anchor position relative to the resized window
+	let scaled_anchor = (
+		transform.source_anchor.0 * scale - window_x as f32 * scale,
+		transform.source_anchor.1 * scale - window_y as f32 * scale,
+	);
+	let offset: XYi =
+		xyf_to_xyi((transform.cell_anchor.0 - scaled_anchor.0, transform.cell_anchor.1 - scaled_anchor.1));
+
+	// Crop to the visible area
+	let cell_rect = (0, 0, cell_size.0, cell_size.1);
+	let image_rect = (offset.0, offset.1, resized_image.width(), resized_image.height());
+	let (x, y, width, height) = intersect(cell_rect, image_rect).filter(|rect| rect.2 > 0 && rect.3 > 0)?;
+	let cropped_image =
+		imageops::crop_imm(&resized_image, (x - offset.0) as u32, (y - offset.1) as u32, width, height)
+			.to_image();
+	Some((DynamicImage::ImageRgb8(cropped_image).into_rgba8(), (x, y)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn transform(scale: f32, angle: f32) -> FaceTransform {
+		FaceTransform {
+			scale,
+			source_anchor: (2.0, 2.0),
+			cell_anchor: (2.0, 2.0),
+			angle,
+		}
+	}
+
+	fn face_with_eyes(left: XYf, right: XYf) -> Face {
+		Face {
+			rect: (0.0, 0.0, 100.0, 100.0),
+			landmarks: vec![left, right, (50.0, 60.0)],
+			confidence: 1.0,
+		}
+	}
+
+	#[test]
+	fn eye_angle_is_positive_when_the_right_eye_is_lower() {
+		let angle = eye_angle(&face_with_eyes((30.0, 40.0), (70.0, 50.0))).unwrap();
+		assert!(angle > 0.0, "{angle}");
+		// The landmark order doesn't matter
+		assert_eq!(eye_angle(&face_with_eyes((70.0, 50.0), (30.0, 40.0))), Some(angle));
+		assert!(eye_angle(&face_with_eyes((30.0, 50.0), (70.0, 40.0))).unwrap() < 0.0);
+		assert_eq!(eye_angle(&face_with_eyes((30.0, 40.0), (70.0, 40.0))), Some(0.0));
+	}
+
+	#[test]
+	fn eyes_transform_places_the_eyes_in_the_cell() {
+		let face = face_with_eyes((30.0, 40.0), (70.0, 40.0));
+		let transform = eyes_transform(&face, 0.25, 0.4, (200, 100)).unwrap();
+		// The midpoint between the eyes is centered, at 40% of the height, and the eyes are 50 pixels apart
+		assert_eq!(transform.source_anchor, (50.0, 40.0));
+		assert_eq!(transform.cell_anchor, (100.0, 40.0));
+		assert_eq!(transform.scale * 40.0, 50.0);
+	}
+
+	#[test]
+	fn eyes_transform_needs_two_distinct_eyes() {
+		let mut face = face_with_eyes((30.0, 40.0), (30.0, 40.0));
+		assert!(eyes_transform(&face, 0.25, 0.4, (100, 100)).is_none());
+		face.landmarks.clear();
+		assert!(eyes_transform(&face, 0.25, 0.4, (100, 100)).is_none());
+		assert!(eye_angle(&face).is_none());
+	}
+
+	#[test]
+	fn render_face_crops_to_the_visible_area() {
+		let image = RgbImage::from_pixel(4, 4, image::Rgb([10, 20, 30]));
+		let transform = FaceTransform {
+			cell_anchor: (1.0, 1.0),
+			..transform(1.0, 0.0)
+		};
+		let (face, offset) = render_face(&image, &transform, (4, 4), ResampleFilter::Nearest).unwrap();
+		assert_eq!(offset, (0, 0));
+		assert_eq!(face.dimensions(), (3, 3));
+		assert_eq!(face.get_pixel(0, 0).0, [10, 20, 30, 255]);
+	}
+
+	#[test]
+	fn render_face_returns_none_when_nothing_is_visible() {
+		let image = RgbImage::new(4, 4);
+		let transform = FaceTransform {
+			cell_anchor: (-10.0, -10.0),
+			..transform(1.0, 0.0)
+		};
+		assert!(render_face(&image, &transform, (4, 4), ResampleFilter::Triangle).is_none());
+	}
+
+	#[test]
+	fn faces_with_an_empty_or_invalid_scale_are_not_visible() {
+		for scale in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+			assert!(!is_visible((4, 4), &transform(scale, 0.0), (4, 4)), "{scale}");
+		}
+		assert!(is_visible((4, 4), &transform(1e-30, 0.0), (4, 4)));
+	}
+
+	#[test]
+	fn rotated_faces_are_sampled_from_the_source() {
+		// A half-turn of a left/right split image swaps the halves, without blurring the split
+		let image = RgbImage::from_fn(4, 4, |x, _| {
+			if x < 2 {
+				image::Rgb([0, 0, 0])
+			} else {
+				image::Rgb([255; 3])
+			}
+		});
+		let (face, offset) =
+			render_face(&image, &transform(1.0, std::f32::consts::PI), (4, 4), ResampleFilter::Triangle)
+				.unwrap();
+		assert_eq!(offset, (0, 0));
+		assert_eq!(face.get_pixel(0, 1).0, [255, 255, 255, 255]);
+		assert_eq!(face.get_pixel(3, 1).0, [0, 0, 0, 255]);
+	}
+
+	/// An image with detail at every scale, to tell filters apart.
+	fn noise(size: u32) -> RgbImage {
+		RgbImage::from_fn(size, size, |x, y| {
+			let value = (x * 7919 + y * 104_729 + x * y * 31) % 256;
+			image::Rgb([value as u8, (255 - value) as u8, (value * 3 % 256) as u8])
+		})
+	}
+
+	#[test]
+	fn rotated_faces_use_the_filter() {
+		let image = noise(16);
+		let filters = [
+			ResampleFilter::Nearest,
+			ResampleFilter::Triangle,
+			ResampleFilter::CatmullRom,
+			ResampleFilter::Gaussian,
+			ResampleFilter::Lanczos3,
+		];
+		let faces =
+			filters.map(|filter| render_face(&image, &transform(0.75, 0.3), (4, 4), filter).unwrap().0);
+		for (index, face) in faces.iter().enumerate() {
+			for (other_index, other) in faces.iter().enumerate().skip(index + 1) {
+				assert_ne!(face, other, "{} and {}", filters[index], filters[other_index]);
+			}
+		}
+	}
+
+	#[test]
+	fn rotated_and_level_faces_are_resampled_alike() {
+		// Without rotation, faces are resized with imageops, which should give the same result as sampling them
+		let image = noise(16);
+		for filter in [ResampleFilter::Triangle, ResampleFilter::CatmullRom, ResampleFilter::Lanczos3] {
+			for scale in [0.5, 2.0] {
+				let transform = FaceTransform {
+					scale,
+					source_anchor: (8.0, 8.0),
+					cell_anchor: (8.0 * scale, 8.0 * scale),
+					angle: 0.0,
+				};
+				let cell_size = whf_to_whi((16.0 * scale, 16.0 * scale));
+				let (level, offset) = render_face(&image, &transform, cell_size, filter).unwrap();
+				let rotated = transform_into_cell(&image, &transform, cell_size, filter);
+				assert_eq!((offset, level.dimensions()), ((0, 0), rotated.dimensions()));
+				for (level_pixel, rotated_pixel) in level.pixels().zip(rotated.pixels()) {
+					for c in 0..4 {
+						let difference = (level_pixel.0[c] as i32 - rotated_pixel.0[c] as i32).abs();
+						assert!(
+							difference <= 1,
+							"{} at {}: {:?} {:?}",
+							filter,
+							scale,
+							level_pixel,
+							rotated_pixel
+						);
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn rotated_faces_are_transparent_outside_the_source() {
+		let image = RgbImage::from_pixel(4, 4, image::Rgb([255; 3]));
+		let transform = FaceTransform {
+			cell_anchor: (0.0, 0.0),
+			..transform(1.0, 0.1)
+		};
+		let (face, _) = render_face(&image, &transform, (8, 8), ResampleFilter::Nearest).unwrap();
+		assert_eq!(face.get_pixel(0, 0).0[3], 255);
+		assert_eq!(face.get_pixel(7, 7).0[3], 0);
+	}
+
+	#[test]
+	fn downscaled_rotations_average_the_source() {
+		// A 1-pixel checkerboard at 1/4 scale averages to gray instead of aliasing to black or white
+		let image = RgbImage::from_fn(16, 16, |x, y| {
+			image::Rgb(
+				[if (x + y) % 2 == 0 {
+					0
+				} else {
+					255
+				}; 3],
+			)
+		});
+		let transform = FaceTransform {
+			scale: 0.25,
+			source_anchor: (8.0, 8.0),
+			cell_anchor: (2.0, 2.0),
+			angle: 1e-6,
+		};
+		let (face, _) = render_face(&image, &transform, (4, 4), ResampleFilter::Triangle).unwrap();
+		let value = face.get_pixel(1, 1).0[0];
+		assert!((96..=160).contains(&value), "{value}");
+	}
+}
@@ -0,0 +1,227 @@
+// Cache of detection results, stored in a central directory (by default "~/.cache/face-grid"), so runs that only
+// change how the grid is rendered don't run the detection model again. Input folders are never written to.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::faces::{Face, FaceSource};
+
+/// Environment variable that sets the face cache directory.
+pub const FACE_CACHE_DIR_ENV: &str = "FACE_GRID_FACE_CACHE_DIR";
+
+/// Size and modification time (in milliseconds) of a file, to tell whether it changed since it was cached.
+type FileStamp = (u64, u64);
+
+/// Contents of a cache file.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+	path: PathBuf,
+	key: String,
+	size: u64,
+	modified: u64,
+	faces: Vec<Face>,
+}
+
+/**
+ * Default directory for the face cache: "face-grid" in "$XDG_CACHE_HOME", or in "~/.cache"
+ */
+pub fn default_dir() -> Option<PathBuf> {
+	let cache_home = std::env::var_os("XDG_CACHE_HOME")
+		.filter(|dir| !dir.is_empty())
+		.map(PathBuf::from)
+		.or_else(|| std::env::home_dir().map(|home| home.join(".cache")))?;
+	Some(cache_home.join("face-grid"))
+}
+
+/// Wraps a face source, reusing the faces found in each image from a file in the cache directory. Cached faces
+/// are only used if the image file and the source configuration (given as a key) are the same as when they
+/// were stored.
+pub struct CachedFaceSource {
+	source: Box<dyn FaceSource>,
+	dir: PathBuf,
+	key: String,
+	refresh: bool,
+	write_failed: AtomicBool,
+}
+
+impl CachedFaceSource {
+	/// Creates a cache for a face source in a directory. With `refresh`, cached faces are ignored and replaced.
+	pub fn new(source: Box<dyn FaceSource>, dir: PathBuf, key: String, refresh: bool) -> Self {
+		Self {
+			source,
+			dir,
+			key,
+			refresh,
+			write_failed: AtomicBool::new(false),
+		}
+	}
+
+	/// The cache file of an image, named after a hash of its absolute path. The hash may change with the Rust
+	/// version, which only means the faces are detected again; the path is also stored to rule out collisions.
+	pub fn cache_path(&self, path: &Path) -> PathBuf {
+		let mut hasher = DefaultHasher::new();
+		absolute_path(path).hash(&mut hasher);
+		self.dir.join(format!("{:016x}.json", hasher.finish()))
+	}
+
+	/// Reads the cached faces of an image, if they're still valid.
+	fn read(&self, path: &Path, stamp: FileStamp) -> Option<Vec<Face>> {
+		let contents = fs::read_to_string(self.cache_path(path)).ok()?;
+		let entry = serde_json::from_str::<CacheEntry>(&contents).ok()?;
+		let is_valid = entry.path == absolute_path(path)
+			&& entry.key == self.key
+			&& (entry.size, entry.modified) == stamp;
+		is_valid.then_some(entry.faces)
+	}
+
+	fn write(&self, path: &Path, stamp: FileStamp, faces: &[Face]) -> std::io::Result<()> {
+		let entry = CacheEntry {
+			path: absolute_path(path),
+			key: self.key.clone(),
+			size: stamp.0,
+			modified: stamp.1,
+			faces: faces.to_vec(),
+		};
+		fs::create_dir_all(&self.dir)?;
+		fs::write(self.cache_path(path), serde_json::to_string_pretty(&entry)?)
+	}
+}
+
+impl FaceSource for CachedFaceSource {
+	fn find_faces(&self, path: &Path, image: &RgbImage) -> Result<Vec<Face>> {
+		let stamp = file_stamp(path);
+		if let Some(faces) = stamp.filter(|_| !self.refresh).and_then(|stamp| self.read(path, stamp)) {
+			return Ok(faces);
+		}
+
+		let faces = self.source.find_faces(path, image)?;
+		if let Some(stamp) = stamp {
+			// The cache is only an optimization, so a failure doesn't stop the run, but it's reported (once)
+			if let Err(err) = self.write(path, stamp, &faces)
+				&& !self.write_failed.swap(true, Ordering::Relaxed)
+			{
+				eprintln!("Warning: could not write to the face cache in {:?}: {}", self.dir, err);
+			}
+		}
+		Ok(faces)
+	}
+}
+
+fn absolute_path(path: &Path) -> PathBuf {
+	fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/**
+ * Size and modification time of a file, to tell whether it changed
+ */
+pub(crate) fn file_stamp(path: &Path) -> Option<FileStamp> {
+	let metadata = fs::metadata(path).ok()?;
+	let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+	Some((metadata.len(), modified.as_millis() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::sync::atomic::AtomicUsize;
+
+	use super::*;
+	use crate::error::Error;
+	use crate::faces::LazyFaceSource;
+	use crate::faces::tests::CountingFaceSource;
+
+	fn test_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("face-grid-cache-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(dir.join("images")).unwrap();
+		dir
+	}
+
+	#[test]
+	fn cached_faces_are_reused_until_the_image_or_key_changes() {
+		let dir = test_dir("reuse");
+		let image_path = dir.join("images").join("a.jpg");
+		fs::write(&image_path, "image").unwrap();
+		let calls = Arc::new(AtomicUsize::new(0));
+		let cache = |key: &str, refresh| {
+			let source = Box::new(CountingFaceSource::new((1.0, 2.0, 3.0, 4.0), &calls));
+			CachedFaceSource::new(source, dir.join("cache"), key.to_string(), refresh)
+		};
+		let image = RgbImage::new(1, 1);
+
+		let faces = cache("a", false).find_faces(&image_path, &image).unwrap();
+		assert_eq!(cache("a", false).find_faces(&image_path, &image).unwrap(), faces);
+		assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+		// Nothing is written next to the images
+		assert_eq!(fs::read_dir(dir.join("images")).unwrap().count(), 1);
+		assert!(cache("a", false).cache_path(&image_path).starts_with(dir.join("cache")));
+
+		cache("b", false).find_faces(&image_path, &image).unwrap();
+		assert_eq!(calls.load(Ordering::Relaxed), 2);
+		cache("b", true).find_faces(&image_path, &image).unwrap();
+		assert_eq!(calls.load(Ordering::Relaxed), 3);
+		fs::write(&image_path, "changed image").unwrap();
+		cache("b", false).find_faces(&image_path, &image).unwrap();
+		assert_eq!(calls.load(Ordering::Relaxed), 4);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn the_source_is_only_built_on_a_cache_miss() {
+		let dir = test_dir("lazy");
+		let image_path = dir.join("images").join("a.jpg");
+		fs::write(&image_path, "image").unwrap();
+		let builds = Arc::new(AtomicUsize::new(0));
+		let cache = || {
+			let builds = builds.clone();
+			let source = LazyFaceSource::new(move || -> Result<Box<dyn FaceSource>> {
+				builds.fetch_add(1, Ordering::Relaxed);
+				Ok(Box::new(CountingFaceSource::new((1.0, 2.0, 3.0, 4.0), &Arc::new(AtomicUsize::new(0)))))
+			});
+			CachedFaceSource::new(Box::new(source), dir.join("cache"), "key".to_string(), false)
+		};
+		let image = RgbImage::new(1, 1);
+
+		let first = cache();
+		first.find_faces(&image_path, &image).unwrap();
+		first.find_faces(&dir.join("images").join("missing.jpg"), &image).unwrap();
+		assert_eq!(builds.load(Ordering::Relaxed), 1);
+		cache().find_faces(&image_path, &image).unwrap();
+		assert_eq!(builds.load(Ordering::Relaxed), 1);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn failing_to_build_the_source_is_fatal() {
+		let source = LazyFaceSource::new(|| Err(Error::Model("no model".to_string())));
+		let err = source.find_faces(Path::new("a.jpg"), &RgbImage::new(1, 1)).unwrap_err();
+		assert!(err.is_fatal());
+	}
+
+	#[test]
+	fn write_errors_are_not_fatal() {
+		let dir = test_dir("readonly");
+		let image_path = dir.join("images").join("a.jpg");
+		fs::write(&image_path, "image").unwrap();
+		// The cache directory can't be created, since a file is in the way
+		fs::write(dir.join("cache"), "").unwrap();
+		let calls = Arc::new(AtomicUsize::new(0));
+		let source = Box::new(CountingFaceSource::new((1.0, 2.0, 3.0, 4.0), &calls));
+		let cache = CachedFaceSource::new(source, dir.join("cache"), "key".to_string(), false);
+		let image = RgbImage::new(1, 1);
+		assert!(cache.find_faces(&image_path, &image).is_ok());
+		assert!(cache.find_faces(&image_path, &image).is_ok());
+		assert!(cache.write_failed.load(Ordering::Relaxed));
+		assert_eq!(calls.load(Ordering::Relaxed), 2);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,241 @@
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::RgbImage;
+use rust_faces::{
+	BlazeFaceParams, FaceDetection, FaceDetector, FaceDetectorBuilder, InferParams, MtCnn, MtCnnParams, Nms,
+	Provider, ToArray3,
+};
+use strum_macros::{Display, EnumString, VariantNames};
+
+use crate::{cache, model};
+
+/// Face detection backends that can be used.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum DetectorKind {
+	BlazeFace640,
+	BlazeFace320,
+	MtCnn,
+}
+
+impl DetectorKind {
+	/// File names of the models needed by the detector, as used by the rust-faces model repository
+	pub fn model_files(&self) -> &'static [&'static str] {
+		match self {
+			DetectorKind::BlazeFace640 => model::BLAZEFACE_640_FILES,
+			DetectorKind::BlazeFace320 => model::BLAZEFACE_320_FILES,
+			DetectorKind::MtCnn => model::MTCNN_FILES,
+		}
+	}
+}
+
+/// Detector choice and tuning parameters.
+#[derive(Clone, Debug)]
+pub struct DetectorConfig {
+	pub kind: DetectorKind,
+	/// Size the image is resized to before running BlazeFace.
+	pub target_size: usize,
+	/// Minimum detection score. For MTCNN, this is the threshold of the last stage. Uses the detector default if None.
+	pub score_threshold: Option<f32>,
+	/// Intersection-over-union threshold for the non-maximum suppression of overlapping detections.
+	pub nms_iou: f32,
+	/// Minimum face size in pixels searched for by MTCNN.
+	pub mtcnn_min_face_size: usize,
+}
+
+impl DetectorConfig {
+	/// Describes everything that changes the detection results, to tell apart results from different configurations.
+	/// Besides the parameters, it includes the model files that `build_face_detector()` would load, with their size
+	/// and modification time, so results from a replaced model aren't reused.
+	pub fn cache_key(&self, model_path: Option<&Path>, cache_dir: Option<&Path>) -> Result<String, String> {
+		let model_files = model::find_model_files(model_path, cache_dir, self.kind.model_files())?;
+		let model = match model_files {
+			Some(files) => files
+				.iter()
+				.map(|file| {
+					let path = fs::canonicalize(file).unwrap_or_else(|_| file.clone());
+					let (size, modified) = cache::file_stamp(file).unwrap_or_default();
+					format!("{:?} (size {}, modified {})", path, size, modified)
+				})
+				.collect::<Vec<String>>()
+				.join(", "),
+			None => "downloaded".to_string(),
+		};
+		Ok(format!(
+			"{}, target size {}, score threshold {:?}, nms iou {}, mtcnn min face size {}, model {}",
+			self.kind, self.target_size, self.score_threshold, self.nms_iou, self.mtcnn_min_face_size, model
+		))
+	}
+
+	fn face_detection(&self) -> FaceDetection {
+		let nms = Nms {
+			iou_threshold: self.nms_iou,
+		};
+		match self.kind {
+			DetectorKind::BlazeFace640 => FaceDetection::BlazeFace640(self.blazeface_params(nms)),
+			DetectorKind::BlazeFace320 => FaceDetection::BlazeFace320(self.blazeface_params(nms)),
+			DetectorKind::MtCnn => {
+				let defaults = MtCnnParams::default();
+				let thresholds = self.score_threshold.map_or(defaults.thresholds, |threshold| {
+					[defaults.thresholds[0], defaults.thresholds[1], threshold]
+				});
+				FaceDetection::MtCnn(MtCnnParams {
+					min_face_size: self.mtcnn_min_face_size,
+					thresholds,
+					nms,
+					..defaults
+				})
+			}
+		}
+	}
+
+	fn blazeface_params(&self, nms: Nms) -> BlazeFaceParams {
+		let defaults = BlazeFaceParams::default();
+		BlazeFaceParams {
+			target_size: self.target_size,
+			score_threshold: self.score_threshold.unwrap_or(defaults.score_threshold),
+			nms,
+			..defaults
+		}
+	}
+}
+
+/**
+ * Create the face detector, loading the model from disk when available
+ */
+pub fn build_face_detector(
+	config: &DetectorConfig,
+	model_path: Option<&Path>,
+	cache_dir: Option<&Path>,
+) -> Result<Box<dyn FaceDetector>, String> {
+	let file_names = config.kind.model_files();
+	let model_files = model::find_model_files(model_path, cache_dir, file_names)?;
+
+	// find_model_files() only checks that local files look like ONNX models, and rust-faces panics when it
+	// can't open a session for a file, or when the model doesn't have the outputs it expects
+	let face_detector = catch_model_panic(|| match &model_files {
+		Some(files) => load_face_detector(config, files),
+		None => FaceDetectorBuilder::new(config.face_detection())
+			.download()
+			.infer_params(infer_params())
+			.build()
+			.map_err(|err| err.to_string()),
+	})
+	.and_then(|result| result)
+	.map_err(|err| format!("Failed to load the face detection model: {}", err))?;
+
+	if let (None, Some(dir)) = (&model_files, cache_dir) {
+		model::store_in_cache(dir, file_names)?;
+	}
+
+	// Run the model once on an empty image, to make sure it's the model we expect
+	let blank_image = RgbImage::new(64, 64).into_array3();
+	let model_name =
+		model_files.as_ref().map_or("downloaded model".to_string(), |files| format!("{:?}", files[0]));
+	let is_expected_model = catch_model_panic(|| face_detector.detect(blank_image.view().into_dyn()).is_ok());
+	if is_expected_model != Ok(true) {
+		return Err(format!("The {} is not a {} face detection model.", model_name, config.kind));
+	}
+
+	Ok(face_detector)
+}
+
+/**
+ * Run model code that panics on unexpected models, turning the panic into an error (without the panic message
+ * that would otherwise be printed)
+ */
+fn catch_model_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+	let default_hook = panic::take_hook();
+	panic::set_hook(Box::new(|_| {}));
+	let result = panic::catch_unwind(AssertUnwindSafe(f));
+	panic::set_hook(default_hook);
+	result.map_err(|payload| {
+		payload
+			.downcast_ref::<String>()
+			.cloned()
+			.or_else(|| payload.downcast_ref::<&str>().map(|message| message.to_string()))
+			.unwrap_or_else(|| "the model could not be run".to_string())
+	})
+}
+
+/**
+ * Inference parameters used for all detectors, whether their models are local or downloaded
+ */
+fn infer_params() -> InferParams {
+	InferParams {
+		provider: Provider::OrtCpu,
+		intra_threads: Some(5),
+		..Default::default()
+	}
+}
+
+/**
+ * Load a detector from local model files
+ */
+fn load_face_detector(config: &DetectorConfig, files: &[PathBuf]) -> Result<Box<dyn FaceDetector>, String> {
+	let paths = files.iter().map(|file| file.to_string_lossy().to_string()).collect::<Vec<String>>();
+
+	match config.face_detection() {
+		FaceDetection::MtCnn(params) => {
+			// The builder only opens a single model file, so the three MTCNN networks are loaded directly, in an
+			// environment with the same (CPU) provider the builder uses for infer_params()
+			let environment =
+				ort::Environment::builder().with_name("face-grid").build().map_err(|err| err.to_string())?;
+			MtCnn::from_file(Arc::new(environment), &paths[0], &paths[1], &paths[2], params)
+				.map(|detector| Box::new(detector) as Box<dyn FaceDetector>)
+				.map_err(|err| err.to_string())
+		}
+		face_detection => FaceDetectorBuilder::new(face_detection)
+			.from_file(paths[0].clone())
+			.infer_params(infer_params())
+			.build()
+			.map_err(|err| err.to_string()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(kind: DetectorKind) -> DetectorConfig {
+		DetectorConfig {
+			kind,
+			target_size: 640,
+			score_threshold: None,
+			nms_iou: 0.3,
+			mtcnn_min_face_size: 24,
+		}
+	}
+
+	#[test]
+	fn cache_keys_change_with_the_parameters_and_the_model() {
+		let dir = std::env::temp_dir().join(format!("face-grid-detector-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let model_path = dir.join("model.onnx");
+		fs::write(&model_path, [0x08, 0x07]).unwrap();
+		let key = |config: DetectorConfig| config.cache_key(Some(&model_path), None).unwrap();
+
+		let first = key(config(DetectorKind::BlazeFace640));
+		assert_eq!(key(config(DetectorKind::BlazeFace640)), first);
+		assert_ne!(key(config(DetectorKind::BlazeFace320)), first);
+		assert_ne!(
+			key(DetectorConfig {
+				nms_iou: 0.5,
+				..config(DetectorKind::BlazeFace640)
+			}),
+			first
+		);
+		// A different model file in the same path
+		fs::write(&model_path, [0x08, 0x07, 0x00]).unwrap();
+		assert_ne!(key(config(DetectorKind::BlazeFace640)), first);
+
+		// Downloaded models can't be told apart until they're in the cache
+		let downloaded = config(DetectorKind::BlazeFace640).cache_key(None, Some(&dir)).unwrap();
+		assert!(downloaded.ends_with("model downloaded"));
+		assert!(config(DetectorKind::BlazeFace640).cache_key(Some(&dir.join("missing.onnx")), None).is_err());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// Everything that can go wrong while building a grid.
+///
+/// Errors are either fatal, stopping the whole process with a specific exit code, or specific to one input
+/// image, in which case the image is skipped.
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("invalid option: {0}")]
+	InvalidOption(String),
+
+	#[error("{0}")]
+	Model(String),
+
+	#[error("invalid input pattern {pattern:?}: {message}")]
+	InvalidInputPattern {
+		pattern: String,
+		message: String,
+	},
+
+	#[error("no valid faces were found in the input images")]
+	NoResults,
+
+	#[error("could not save output to {path:?}: {message}")]
+	SaveOutput {
+		path: PathBuf,
+		message: String,
+	},
+
+	#[error("could not read manifest {path:?}: {message}")]
+	ReadManifest {
+		path: PathBuf,
+		message: String,
+	},
+
+	#[error("could not start worker threads: {0}")]
+	WorkerThreads(String),
+
+	#[error("could not read file: {0}")]
+	ReadFile(String),
+
+	#[error("invalid image: {0}")]
+	InvalidImage(#[from] image::ImageError),
+
+	#[error("could not find faces: {0}")]
+	FaceDetection(String),
+
+	#[error("no valid faces")]
+	NoFaces,
+
+	#[error("face is outside of the cell")]
+	OutsideCell,
+}
+
+impl Error {
+	/// Exit code used when the error stops the process. Errors that only skip an image use 1.
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			Error::InvalidOption(_) => 2,
+			Error::Model(_) => 3,
+			Error::InvalidInputPattern {
+				..
+			} => 4,
+			Error::NoResults => 5,
+			Error::SaveOutput {
+				..
+			} => 6,
+			Error::ReadManifest {
+				..
+			} => 7,
+			Error::WorkerThreads(_) => 8,
+			_ => 1,
+		}
+	}
+
+	/// Whether the error stops the process, instead of only skipping an image.
+	pub fn is_fatal(&self) -> bool {
+		self.exit_code() != 1
+	}
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
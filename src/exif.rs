@@ -0,0 +1,207 @@
+// Reading of the EXIF orientation and date tags, from any image format with EXIF data (JPEG, PNG, TIFF, WebP,
+// and HEIF).
+// More info: https://www.media.mit.edu/pia/Research/deepview/exif.html
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use ::exif::{Exif, In, Reader, Tag, Value};
+use image::DynamicImage;
+
+/**
+ * Read the EXIF orientation (1 to 8) of an image file, if it has one
+ */
+pub fn read_orientation(path: &Path) -> Option<u16> {
+	let exif = read_exif(path)?;
+	let orientation = exif.get_field(Tag::Orientation, In::PRIMARY)?.value.get_uint(0)?;
+	u16::try_from(orientation).ok().filter(|orientation| (1..=8).contains(orientation))
+}
+
+/**
+ * Read the date a photo was taken ("YYYY:MM:DD HH:MM:SS"), or else when it was last changed, if it has one
+ */
+pub fn read_date(path: &Path) -> Option<String> {
+	let exif = read_exif(path)?;
+	read_ascii(&exif, Tag::DateTimeOriginal).or_else(|| read_ascii(&exif, Tag::DateTime))
+}
+
+fn read_exif(path: &Path) -> Option<Exif> {
+	let mut reader = BufReader::new(File::open(path).ok()?);
+	Reader::new().read_from_container(&mut reader).ok()
+}
+
+fn read_ascii(exif: &Exif, tag: Tag) -> Option<String> {
+	match &exif.get_field(tag, In::PRIMARY)?.value {
+		Value::Ascii(values) => {
+			let text = String::from_utf8_lossy(values.first()?);
+			Some(text.trim_end_matches('\0').trim().to_string()).filter(|text| !text.is_empty())
+		}
+		_ => None,
+	}
+}
+
+/**
+ * Rotate and flip an image according to its EXIF orientation, so it's upright
+ */
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+	match orientation {
+		2 => image.fliph(),
+		3 => image.rotate180(),
+		4 => image.flipv(),
+		5 => image.rotate90().fliph(),
+		6 => image.rotate90(),
+		7 => image.rotate270().fliph(),
+		8 => image.rotate270(),
+		_ => image,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::io::Cursor;
+	use std::path::PathBuf;
+
+	use ::exif::Field;
+	use ::exif::experimental::Writer;
+	use image::{GenericImageView, Rgb, RgbImage};
+
+	use super::*;
+
+	/// TIFF data with the given EXIF fields, as stored inside image files.
+	fn tiff_data(fields: &[Field], little_endian: bool) -> Vec<u8> {
+		let mut writer = Writer::new();
+		for field in fields {
+			writer.push_field(field);
+		}
+		let mut data = Cursor::new(vec![]);
+		writer.write(&mut data, little_endian).unwrap();
+		data.into_inner()
+	}
+
+	/// Writes a JPEG file with the given EXIF fields in its APP1 segment, returning its path.
+	fn jpeg_with_exif(dir: &Path, name: &str, fields: &[Field], little_endian: bool) -> PathBuf {
+		let tiff = tiff_data(fields, little_endian);
+		let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+		jpeg.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+		jpeg.extend_from_slice(b"Exif\0\0");
+		jpeg.extend_from_slice(&tiff);
+		jpeg.extend_from_slice(&[0xff, 0xd9]);
+		write_file(dir, name, &jpeg)
+	}
+
+	fn test_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("face-grid-exif-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	fn write_file(dir: &Path, name: &str, contents: &[u8]) -> PathBuf {
+		let path = dir.join(name);
+		fs::write(&path, contents).unwrap();
+		path
+	}
+
+	fn orientation_field(orientation: u16) -> Field {
+		Field {
+			tag: Tag::Orientation,
+			ifd_num: In::PRIMARY,
+			value: Value::Short(vec![orientation]),
+		}
+	}
+
+	fn date_field(tag: Tag, date: &str) -> Field {
+		Field {
+			tag,
+			ifd_num: In::PRIMARY,
+			value: Value::Ascii(vec![date.as_bytes().to_vec()]),
+		}
+	}
+
+	#[test]
+	fn reads_orientation_in_both_byte_orders() {
+		let dir = test_dir("byte-orders");
+		for little_endian in [true, false] {
+			for orientation in 1..=8 {
+				let name = format!("orientation-{}-{}.jpg", orientation, little_endian);
+				let path = jpeg_with_exif(&dir, &name, &[orientation_field(orientation)], little_endian);
+				assert_eq!(read_orientation(&path), Some(orientation));
+			}
+		}
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn ignores_invalid_or_missing_orientation() {
+		let dir = test_dir("invalid");
+		let path = jpeg_with_exif(&dir, "orientation-9.jpg", &[orientation_field(9)], true);
+		assert_eq!(read_orientation(&path), None);
+
+		let path = jpeg_with_exif(
+			&dir,
+			"no-orientation.jpg",
+			&[date_field(Tag::DateTime, "2020:01:02 03:04:05")],
+			true,
+		);
+		assert_eq!(read_orientation(&path), None);
+
+		let path = write_file(&dir, "not-an-image.jpg", b"not an image");
+		assert_eq!(read_orientation(&path), None);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn reads_orientation_from_tiff_files() {
+		let dir = test_dir("tiff");
+		let path = write_file(&dir, "orientation.tif", &tiff_data(&[orientation_field(6)], false));
+		assert_eq!(read_orientation(&path), Some(6));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn reads_the_original_date_first() {
+		let dir = test_dir("dates");
+		for little_endian in [true, false] {
+			let fields = [
+				date_field(Tag::DateTime, "2021:01:01 00:00:00"),
+				date_field(Tag::DateTimeOriginal, "2020:06:15 12:30:00"),
+			];
+			let path = jpeg_with_exif(&dir, &format!("dates-{}.jpg", little_endian), &fields, little_endian);
+			assert_eq!(read_date(&path).as_deref(), Some("2020:06:15 12:30:00"));
+		}
+
+		let path = jpeg_with_exif(
+			&dir,
+			"modified-date.jpg",
+			&[date_field(Tag::DateTime, "2021:01:01 00:00:00")],
+			true,
+		);
+		assert_eq!(read_date(&path).as_deref(), Some("2021:01:01 00:00:00"));
+
+		let path = jpeg_with_exif(&dir, "no-date.jpg", &[orientation_field(1)], true);
+		assert_eq!(read_date(&path), None);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn applies_every_orientation() {
+		// A 2x1 image with a red pixel at the left, and a blue one at the right
+		let red = Rgb([255, 0, 0]);
+		let blue = Rgb([0, 0, 255]);
+		let mut image = RgbImage::new(2, 1);
+		image.put_pixel(0, 0, red);
+		image.put_pixel(1, 0, blue);
+
+		// Where the red pixel ends up, and the size of the upright image
+		let expected = [(1, (0, 0), (2, 1)), (2, (1, 0), (2, 1)), (3, (1, 0), (2, 1)), (4, (0, 0), (2, 1))]
+			.into_iter()
+			.chain([(5, (0, 0), (1, 2)), (6, (0, 0), (1, 2)), (7, (0, 1), (1, 2)), (8, (0, 1), (1, 2))]);
+		for (orientation, (red_x, red_y), size) in expected {
+			let upright = apply_orientation(DynamicImage::ImageRgb8(image.clone()), orientation);
+			assert_eq!(upright.dimensions(), size, "orientation {}", orientation);
+			assert_eq!(upright.to_rgb8().get_pixel(red_x, red_y), &red, "orientation {}", orientation);
+		}
+	}
+}
@@ -0,0 +1,370 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use image::RgbImage;
+use rust_faces::{FaceDetector, ToArray3};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString, VariantNames};
+
+use crate::error::{Error, Result};
+use crate::geom::{Length, WHi, XYWHf, XYf};
+
+/// A face found in an image, in source image pixel coordinates.
+///
+/// In JSON, a face is an object with a "rect" (`[x, y, width, height]`, in pixels), and optional "landmarks"
+/// (`[[x, y], ...]`) and "confidence" (1 if omitted).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Face {
+	pub rect: XYWHf,
+	/// Face landmarks, when available. For BlazeFace: eyes, nose, and mouth corners.
+	#[serde(default)]
+	pub landmarks: Vec<XYf>,
+	#[serde(default = "default_confidence")]
+	pub confidence: f32,
+}
+
+fn default_confidence() -> f32 {
+	1.0
+}
+
+/// Anything that can tell where the faces are in an image.
+///
+/// Errors are usually `Error::FaceDetection`, skipping the image; fatal errors (such as `Error::Model`) stop
+/// processing.
+pub trait FaceSource: Sync + Send {
+	fn find_faces(&self, path: &Path, image: &RgbImage) -> Result<Vec<Face>>;
+}
+
+/// Where to get face positions from.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum FaceSourceKind {
+	/// Run the face detection model.
+	Detector,
+	/// Read faces from a JSON file next to each image.
+	Sidecar,
+	/// Use the same rectangle for every image.
+	Fixed,
+}
+
+/// Finds faces using a rust-faces detector.
+pub struct DetectorFaceSource {
+	pub detector: Box<dyn FaceDetector>,
+}
+
+impl FaceSource for DetectorFaceSource {
+	fn find_faces(&self, _path: &Path, image: &RgbImage) -> Result<Vec<Face>> {
+		let array3_image = image.clone().into_array3();
+		let faces = self
+			.detector
+			.detect(array3_image.view().into_dyn())
+			.map_err(|err| Error::FaceDetection(err.to_string()))?;
+		Ok(faces
+			.into_iter()
+			.map(|face| Face {
+				rect: (face.rect.x, face.rect.y, face.rect.width, face.rect.height),
+				landmarks: face.landmarks.unwrap_or_default(),
+				confidence: face.confidence,
+			})
+			.collect())
+	}
+}
+
+/// Reads faces from a "<image file name>.faces.json" file next to each image, e.g. as written by other tools.
+///
+/// The file contains either a list of faces, or an object with a "faces" list.
+pub struct SidecarFaceSource;
+
+impl SidecarFaceSource {
+	pub fn sidecar_path(path: &Path) -> PathBuf {
+		let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+		file_name.push(".faces.json");
+		path.with_file_name(file_name)
+	}
+}
+
+impl FaceSource for SidecarFaceSource {
+	fn find_faces(&self, path: &Path, _image: &RgbImage) -> Result<Vec<Face>> {
+		let sidecar_path = Self::sidecar_path(path);
+		let contents = fs::read_to_string(&sidecar_path)
+			.map_err(|err| Error::FaceDetection(format!("Could not read {:?}: {}", sidecar_path, err)))?;
+		let invalid = |message: String| Error::FaceDetection(format!("{:?}: {}", sidecar_path, message));
+		let document =
+			serde_json::from_str::<serde_json::Value>(&contents).map_err(|err| invalid(err.to_string()))?;
+		let faces = match document {
+			serde_json::Value::Object(mut object) => {
+				object.remove("faces").ok_or_else(|| invalid("expected a list of faces".to_string()))?
+			}
+			faces => faces,
+		};
+		serde_json::from_value(faces).map_err(|err| invalid(err.to_string()))
+	}
+}
+
+/// Uses the same face rectangle for every image, for images that are already aligned.
+pub struct FixedFaceSource {
+	pub rect: XYWHf,
+}
+
+impl FaceSource for FixedFaceSource {
+	fn find_faces(&self, _path: &Path, _image: &RgbImage) -> Result<Vec<Face>> {
+		Ok(vec![Face {
+			rect: self.rect,
+			landmarks: vec![],
+			confidence: 1.0,
+		}])
+	}
+}
+
+/// Builds a face source the first time it's needed, e.g. so the detection model is only loaded when the
+/// cache misses. If it can't be built, every image fails with the same fatal error.
+pub struct LazyFaceSource {
+	build: Box<dyn Fn() -> Result<Box<dyn FaceSource>> + Sync + Send>,
+	source: OnceLock<std::result::Result<Box<dyn FaceSource>, String>>,
+}
+
+impl LazyFaceSource {
+	pub fn new(build: impl Fn() -> Result<Box<dyn FaceSource>> + Sync + Send + 'static) -> Self {
+		Self {
+			build: Box::new(build),
+			source: OnceLock::new(),
+		}
+	}
+}
+
+impl FaceSource for LazyFaceSource {
+	fn find_faces(&self, path: &Path, image: &RgbImage) -> Result<Vec<Face>> {
+		match self.source.get_or_init(|| (self.build)().map_err(|err| err.to_string())) {
+			Ok(source) => source.find_faces(path, image),
+			Err(message) => Err(Error::Model(message.clone())),
+		}
+	}
+}
+
+/**
+ * Remove faces below the minimum confidence or size. The size of a face is its shorter side, and fractions
+ * are relative to the shorter side of the image
+ */
+pub fn filter_faces(
+	faces: Vec<Face>,
+	min_confidence: f32,
+	min_face_size: Option<Length>,
+	image_size: WHi,
+) -> Vec<Face> {
+	let min_size = min_face_size.map_or(0.0, |size| size.to_pixels(image_size.0.min(image_size.1) as f32));
+	faces
+		.into_iter()
+		.filter(|face| face.confidence >= min_confidence && face.rect.2.min(face.rect.3) >= min_size)
+		.collect()
+}
+
+/// What to do with images where more than one face is found.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum MultiFacePolicy {
+	/// Skip the image.
+	Skip,
+	/// Use the face with the largest rectangle.
+	Largest,
+	/// Use the face with the highest detection confidence.
+	MostConfident,
+	/// Use the face closest to the center of the image.
+	CenterMost,
+	/// Use all faces, one grid cell each.
+	All,
+}
+
+/**
+ * Pick the faces to use from an image, according to the multi-face policy
+ */
+pub fn select_faces(faces: Vec<Face>, policy: MultiFacePolicy, image_size: WHi) -> Vec<Face> {
+	if faces.len() <= 1 || policy == MultiFacePolicy::All {
+		return faces;
+	}
+
+	let center = (image_size.0 as f32 / 2.0, image_size.1 as f32 / 2.0);
+	let distance_to_center = |face: &Face| {
+		let face_center = (face.rect.0 + face.rect.2 / 2.0, face.rect.1 + face.rect.3 / 2.0);
+		(face_center.0 - center.0).powi(2) + (face_center.1 - center.1).powi(2)
+	};
+
+	let selected = match policy {
+		MultiFacePolicy::Skip | MultiFacePolicy::All => None,
+		MultiFacePolicy::Largest => {
+			faces.into_iter().max_by(|a, b| (a.rect.2 * a.rect.3).total_cmp(&(b.rect.2 * b.rect.3)))
+		}
+		MultiFacePolicy::MostConfident => {
+			faces.into_iter().max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+		}
+		MultiFacePolicy::CenterMost => {
+			faces.into_iter().min_by(|a, b| distance_to_center(a).total_cmp(&distance_to_center(b)))
+		}
+	};
+	selected.into_iter().collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	/// Finds a fixed face, counting how many times it's asked to.
+	pub(crate) struct CountingFaceSource {
+		source: FixedFaceSource,
+		calls: Arc<AtomicUsize>,
+	}
+
+	impl CountingFaceSource {
+		pub fn new(rect: XYWHf, calls: &Arc<AtomicUsize>) -> Self {
+			Self {
+				source: FixedFaceSource {
+					rect,
+				},
+				calls: calls.clone(),
+			}
+		}
+	}
+
+	impl FaceSource for CountingFaceSource {
+		fn find_faces(&self, path: &Path, image: &RgbImage) -> Result<Vec<Face>> {
+			self.calls.fetch_add(1, Ordering::Relaxed);
+			self.source.find_faces(path, image)
+		}
+	}
+
+	fn face(rect: XYWHf, confidence: f32) -> Face {
+		Face {
+			rect,
+			landmarks: vec![],
+			confidence,
+		}
+	}
+
+	fn test_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("face-grid-faces-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// Writes a sidecar file for a (nonexistent) image in a directory, returning the image path.
+	fn image_with_sidecar(dir: &Path, name: &str, contents: &str) -> PathBuf {
+		let path = dir.join(name);
+		fs::write(SidecarFaceSource::sidecar_path(&path), contents).unwrap();
+		path
+	}
+
+	#[test]
+	fn fixed_source_returns_the_same_face_for_every_image() {
+		let source = FixedFaceSource {
+			rect: (10.0, 20.0, 30.0, 40.0),
+		};
+		let image = RgbImage::new(1, 1);
+		let expected = vec![face((10.0, 20.0, 30.0, 40.0), 1.0)];
+		assert_eq!(source.find_faces(Path::new("a.jpg"), &image).unwrap(), expected);
+		assert_eq!(source.find_faces(Path::new("b.jpg"), &image).unwrap(), expected);
+	}
+
+	#[test]
+	fn sidecar_source_reads_a_list_of_faces() {
+		let dir = test_dir("list");
+		let path = image_with_sidecar(
+			&dir,
+			"list.jpg",
+			r#"[{"rect": [1, 2, 3, 4], "landmarks": [[5, 6], [7, 8]], "confidence": 0.5}, {"rect": [9, 10, 11, 12]}]"#,
+		);
+		let faces = SidecarFaceSource.find_faces(&path, &RgbImage::new(1, 1)).unwrap();
+		assert_eq!(
+			faces,
+			vec![
+				Face {
+					rect: (1.0, 2.0, 3.0, 4.0),
+					landmarks: vec![(5.0, 6.0), (7.0, 8.0)],
+					confidence: 0.5,
+				},
+				face((9.0, 10.0, 11.0, 12.0), 1.0),
+			]
+		);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn sidecar_source_reads_an_object_with_faces() {
+		let dir = test_dir("object");
+		let path = image_with_sidecar(&dir, "object.jpg", r#"{"faces": [{"rect": [1, 2, 3, 4]}]}"#);
+		let faces = SidecarFaceSource.find_faces(&path, &RgbImage::new(1, 1)).unwrap();
+		assert_eq!(faces, vec![face((1.0, 2.0, 3.0, 4.0), 1.0)]);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn sidecar_source_rejects_invalid_files() {
+		let dir = test_dir("invalid");
+		let image = RgbImage::new(1, 1);
+		let missing = std::env::temp_dir().join("face-grid-missing.jpg");
+		assert!(SidecarFaceSource.find_faces(&missing, &image).is_err());
+
+		let bad_rect = image_with_sidecar(&dir, "bad-rect.jpg", r#"[{"rect": [1, 2, 3]}]"#);
+		assert!(SidecarFaceSource.find_faces(&bad_rect, &image).is_err());
+
+		let no_faces = image_with_sidecar(&dir, "no-faces.jpg", r#"{"rects": []}"#);
+		assert!(SidecarFaceSource.find_faces(&no_faces, &image).is_err());
+
+		// Deep nesting is an error, not a stack overflow
+		let nested = image_with_sidecar(&dir, "nested.jpg", &"[".repeat(100_000));
+		assert!(SidecarFaceSource.find_faces(&nested, &image).is_err());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn filter_faces_removes_unconfident_and_small_faces() {
+		let faces = vec![
+			face((0.0, 0.0, 50.0, 20.0), 0.9),
+			face((0.0, 0.0, 30.0, 30.0), 0.5),
+			face((0.0, 0.0, 10.0, 40.0), 0.95),
+		];
+		let image_size = (200, 100);
+
+		assert_eq!(filter_faces(faces.clone(), 0.0, None, image_size), faces);
+		assert_eq!(
+			filter_faces(faces.clone(), 0.8, None, image_size),
+			vec![faces[0].clone(), faces[2].clone()]
+		);
+		assert_eq!(
+			filter_faces(faces.clone(), 0.0, Some(Length::Pixels(20.0)), image_size),
+			vec![faces[0].clone(), faces[1].clone()]
+		);
+		// Fractions are relative to the shorter side of the image (100 pixels)
+		assert_eq!(
+			filter_faces(faces.clone(), 0.0, Some(Length::Fraction(0.25)), image_size),
+			vec![faces[1].clone()]
+		);
+	}
+
+	#[test]
+	fn select_faces_follows_the_policy() {
+		let image_size = (100, 100);
+		let faces = vec![
+			face((0.0, 0.0, 40.0, 40.0), 0.6),
+			face((40.0, 40.0, 20.0, 20.0), 0.7),
+			face((70.0, 0.0, 30.0, 30.0), 0.9),
+		];
+		let select = |policy| select_faces(faces.clone(), policy, image_size);
+
+		assert_eq!(select(MultiFacePolicy::Skip), vec![]);
+		assert_eq!(select(MultiFacePolicy::All), faces);
+		assert_eq!(select(MultiFacePolicy::Largest), vec![faces[0].clone()]);
+		assert_eq!(select(MultiFacePolicy::MostConfident), vec![faces[2].clone()]);
+		assert_eq!(select(MultiFacePolicy::CenterMost), vec![faces[1].clone()]);
+	}
+
+	#[test]
+	fn select_faces_keeps_a_single_face() {
+		let faces = vec![face((0.0, 0.0, 10.0, 10.0), 0.5)];
+		assert_eq!(select_faces(faces.clone(), MultiFacePolicy::Skip, (100, 100)), faces);
+		assert_eq!(select_faces(vec![], MultiFacePolicy::Largest, (100, 100)), vec![]);
+	}
+}
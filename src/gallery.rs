@@ -0,0 +1,151 @@
+// HTML page showing a rendered grid, with each cell linking to its original file.
+
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::grid::{Cell, GridLayout};
+
+/**
+ * Creates an HTML page showing the output images of a grid (one per page), with a link over each cell to its
+ * source file, and the file name and confidence on hover. Links are relative to the directory of the page, when
+ * possible.
+ */
+pub fn gallery_html(page_path: &Path, outputs: &[PathBuf], layout: &GridLayout, cells: &[Cell]) -> String {
+	let page_dir = page_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+	let (output_width, output_height) = layout.output_size();
+	let percent = |value: i32, total: u32| value as f32 * 100.0 / total as f32;
+
+	let title = outputs.first().and_then(|output| output.file_name()).unwrap_or_default().to_string_lossy();
+	let mut html = String::new();
+	html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+	writeln!(html, "<title>{}</title>", escape(&title)).unwrap();
+	html.push_str("<style>\n");
+	html.push_str("\t.grid { position: relative; max-width: 100%; margin-bottom: 1em; }\n");
+	html.push_str("\t.grid img { display: block; width: 100%; height: auto; }\n");
+	html.push_str("\t.grid a { position: absolute; }\n");
+	html.push_str("\t.grid a:hover { outline: 2px solid #fff; }\n");
+	html.push_str("</style>\n</head>\n<body>\n");
+	for (page, output) in outputs.iter().enumerate() {
+		writeln!(html, "<div class=\"grid\" style=\"width: {}px\">", output_width).unwrap();
+		writeln!(
+			html,
+			"\t<img src=\"{}\" width=\"{}\" height=\"{}\" alt=\"\">",
+			escape(&link(page_dir, output)),
+			output_width,
+			output_height
+		)
+		.unwrap();
+		for (index, cell) in cells.iter().enumerate().filter(|(index, _)| layout.page(*index) == page) {
+			let (x, y, width, height) = layout.cell_rect(index);
+			let file_name = cell.path.file_name().unwrap_or(cell.path.as_os_str()).to_string_lossy();
+			writeln!(
+				html,
+				"\t<a href=\"{}\" title=\"{} (confidence {})\" style=\"left: {}%; top: {}%; width: {}%; height: {}%\"></a>",
+				escape(&link(page_dir, &cell.path)),
+				escape(&file_name),
+				cell.face.confidence,
+				percent(x, output_width),
+				percent(y, output_height),
+				percent(width as i32, output_width),
+				percent(height as i32, output_height),
+			)
+			.unwrap();
+		}
+		html.push_str("</div>\n");
+	}
+	html.push_str("</body>\n</html>\n");
+	html
+}
+
+/**
+ * Creates a URL to a file, relative to a directory if both can be resolved, or absolute otherwise
+ */
+fn link(from_dir: &Path, path: &Path) -> String {
+	let absolute_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+	let target = from_dir
+		.canonicalize()
+		.ok()
+		.and_then(|from_dir| relative_path(&from_dir, &absolute_path))
+		.unwrap_or(absolute_path);
+
+	let parts = target
+		.components()
+		.filter_map(|component| match component {
+			Component::RootDir => None,
+			Component::Prefix(prefix) => Some(prefix.as_os_str().to_string_lossy().into_owned()),
+			component => Some(encode_url_part(&component.as_os_str().to_string_lossy())),
+		})
+		.collect::<Vec<String>>();
+	if target.is_absolute() {
+		format!("file:///{}", parts.join("/"))
+	} else {
+		parts.join("/")
+	}
+}
+
+/**
+ * Finds the path to a file from a directory, both absolute
+ */
+fn relative_path(from_dir: &Path, path: &Path) -> Option<PathBuf> {
+	let from = from_dir.components().collect::<Vec<_>>();
+	let to = path.components().collect::<Vec<_>>();
+	let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+	// Paths on different drives can't be relative to each other
+	if common == 0 {
+		return None;
+	}
+
+	let mut relative = PathBuf::new();
+	for _ in common..from.len() {
+		relative.push("..");
+	}
+	for component in &to[common..] {
+		relative.push(component);
+	}
+	Some(relative)
+}
+
+fn encode_url_part(part: &str) -> String {
+	let mut encoded = String::new();
+	for byte in part.bytes() {
+		if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+			encoded.push(byte as char);
+		} else {
+			write!(encoded, "%{:02X}", byte).unwrap();
+		}
+	}
+	encoded
+}
+
+fn escape(text: &str) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn paths_are_relative_to_the_page_directory() {
+		let relative = |from: &str, path: &str| relative_path(Path::new(from), Path::new(path));
+		assert_eq!(relative("/photos/grid", "/photos/grid/a.jpg"), Some(PathBuf::from("a.jpg")));
+		assert_eq!(relative("/photos/grid", "/photos/2020/a.jpg"), Some(PathBuf::from("../2020/a.jpg")));
+		assert_eq!(relative("/photos/grid/pages", "/a.jpg"), Some(PathBuf::from("../../../a.jpg")));
+		assert_eq!(relative("/photos", "/photos/2020/01/a.jpg"), Some(PathBuf::from("2020/01/a.jpg")));
+		assert_eq!(relative("/photos", "photos/a.jpg"), None);
+	}
+
+	#[test]
+	fn links_are_url_encoded() {
+		let dir = std::env::temp_dir();
+		assert_eq!(link(&dir, &dir.join("a b").join("c#d.jpg")), "a%20b/c%23d.jpg");
+		assert_eq!(link(&dir, &dir.join("caf\u{e9}.jpg")), "caf%C3%A9.jpg");
+		assert_eq!(link(Path::new("/missing directory"), Path::new("/a b.jpg")), "file:///a%20b.jpg");
+	}
+
+	#[test]
+	fn text_is_escaped_for_html() {
+		assert_eq!(escape("a & <b> \"c\""), "a &amp; &lt;b&gt; &quot;c&quot;");
+		assert_eq!(escape("plain.jpg"), "plain.jpg");
+	}
+}
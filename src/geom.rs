@@ -7,6 +7,23 @@ pub type WHi = (u32, u32);
 pub type XYXYi = (i32, i32, i32, i32);
 pub type XYWHi = (i32, i32, u32, u32);
 
+/// A length given either in pixels, or as a fraction of some other length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Length {
+	Pixels(f32),
+	Fraction(f32),
+}
+
+impl Length {
+	/// Resolves the length in pixels, with fractions relative to the given length.
+	pub fn to_pixels(&self, relative_to: f32) -> f32 {
+		match self {
+			Length::Pixels(pixels) => *pixels,
+			Length::Fraction(fraction) => fraction * relative_to,
+		}
+	}
+}
+
 /**
  * Find the expected scale to fit a rectangle (w, h) inside another.
  */
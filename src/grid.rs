@@ -0,0 +1,1412 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+use image::{ImageBuffer, RgbImage, Rgba, RgbaImage};
+use strum_macros::{Display, EnumString, VariantNames};
+
+use crate::align::{self, AlignMode, FaceTransform, ResampleFilter};
+use crate::error::{Error, Result};
+use crate::exif;
+use crate::faces::{Face, FaceSource, MultiFacePolicy, filter_faces, select_faces};
+use crate::geom::{Length, WHf, WHi, XYWHi, XYi, fit_inside, intersect};
+use crate::sort::{self, SortOrder};
+
+/// What was found in an input image, and the faces that will be used from it.
+#[derive(Clone, Debug)]
+pub struct Detection {
+	pub path: PathBuf,
+	/// Dimensions of the image as stored in the file, before any reorientation.
+	pub original_size: WHi,
+	/// Dimensions of the upright image, which the faces are relative to.
+	pub size: WHi,
+	/// EXIF orientation applied to make the image upright, if any.
+	pub orientation: Option<u16>,
+	/// Number of faces found in the image.
+	pub num_faces_found: usize,
+	/// Number of faces left after the confidence and size filters.
+	pub num_faces_accepted: usize,
+	/// Faces to use, after applying the multi-face policy.
+	pub faces: Vec<Face>,
+}
+
+/// A face, aligned and rendered for its grid cell.
+pub struct Cell {
+	pub path: PathBuf,
+	pub face: Face,
+	pub transform: FaceTransform,
+	/// The rendered image, cropped to the part that is visible in the cell.
+	pub image: RgbaImage,
+	/// Position of the rendered image inside the cell.
+	pub offset: XYi,
+}
+
+/// What happened to one input image.
+pub struct ImageOutcome {
+	pub path: PathBuf,
+	/// What was found in the image, if it could be read.
+	pub detection: Option<Detection>,
+	/// The cells created from the image, or why it was skipped.
+	pub cells: Result<Vec<Cell>>,
+}
+
+/// How cells are split into pages, each one a separate output image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageSize {
+	/// A maximum number of cells in each page.
+	Cells(usize),
+	/// A maximum number of rows in each page.
+	Rows(u32),
+}
+
+/// How much farther from the target aspect ratio than the best column count another one can be, when looking
+/// for fewer empty cells.
+const ASPECT_TOLERANCE: f32 = 1.25;
+
+/// Where the cells of a last row that isn't full are placed.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum LastRow {
+	/// Aligned to the left, like the other rows.
+	Left,
+	/// Centered in the row.
+	Center,
+	/// Spread evenly across the row, so it spans the same width as the full rows. Also accepted as "stretch";
+	/// the cells keep their size.
+	#[strum(to_string = "spread", serialize = "stretch")]
+	Spread,
+}
+
+impl LastRow {
+	/// Names that can be parsed, including aliases (which `VARIANTS` leaves out).
+	pub const NAMES: &[&str] = &["left", "center", "spread", "stretch"];
+}
+
+/// A target for the output image of each page, that the columns and cell size are solved for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFit {
+	/// An exact output size, in pixels.
+	Size(WHi),
+	/// An output aspect ratio (width / height), keeping the cell size.
+	Aspect(f32),
+}
+
+impl OutputFit {
+	/// Size of the output image for a grid of the given size; the grid is centered in it. A grid solved with
+	/// `FaceGrid::fit_to_output()` always fits in an output size; the output only grows for grids that weren't,
+	/// so no cells are cut off.
+	pub fn output_size(&self, grid_size: WHi) -> WHi {
+		match *self {
+			OutputFit::Size(size) => (size.0.max(grid_size.0), size.1.max(grid_size.1)),
+			OutputFit::Aspect(aspect) => {
+				if (grid_size.0 as f32) < grid_size.1 as f32 * aspect {
+					((grid_size.1 as f32 * aspect).round() as u32, grid_size.1)
+				} else {
+					(grid_size.0, (grid_size.0 as f32 / aspect).round() as u32)
+				}
+			}
+		}
+	}
+}
+
+/// Arrangement of cells in the output images. All pages have the same arrangement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridLayout {
+	pub columns: u32,
+	pub rows: u32,
+	pub cell_size: WHi,
+	/// Horizontal and vertical space between cells.
+	pub gutter: WHi,
+	/// Horizontal and vertical space around the grid.
+	pub margin: WHi,
+	pub cells_per_page: usize,
+	pub pages: usize,
+	/// Target for the output size, if any.
+	pub output_fit: Option<OutputFit>,
+	/// Number of cells in all pages.
+	pub num_cells: usize,
+	pub last_row: LastRow,
+}
+
+impl GridLayout {
+	/// Page where a cell is.
+	pub fn page(&self, index: usize) -> usize {
+		index / self.cells_per_page
+	}
+
+	/// Dimensions of the grid in each page, including its margins.
+	pub fn grid_size(&self) -> WHi {
+		let gutters =
+			(self.columns.saturating_sub(1) * self.gutter.0, self.rows.saturating_sub(1) * self.gutter.1);
+		(
+			self.columns * self.cell_size.0 + gutters.0 + self.margin.0 * 2,
+			self.rows * self.cell_size.1 + gutters.1 + self.margin.1 * 2,
+		)
+	}
+
+	/// Dimensions of the output image of each page.
+	pub fn output_size(&self) -> WHi {
+		let grid_size = self.grid_size();
+		self.output_fit.map_or(grid_size, |output_fit| output_fit.output_size(grid_size))
+	}
+
+	/// Column and row of a cell, inside its page.
+	pub fn cell_position(&self, index: usize) -> (u32, u32) {
+		let index_in_page = (index % self.cells_per_page) as u32;
+		(index_in_page % self.columns, index_in_page / self.columns)
+	}
+
+	/// Rectangle of a cell in the output image of its page.
+	pub fn cell_rect(&self, index: usize) -> XYWHi {
+		let (col, row) = self.cell_position(index);
+		let (grid_width, grid_height) = self.grid_size();
+		let (output_width, output_height) = self.output_size();
+		let cell_step = self.cell_size.0 + self.gutter.0;
+
+		// The last row of a page might not be full
+		let page = self.page(index);
+		let cells_in_page = (self.num_cells - page * self.cells_per_page).min(self.cells_per_page) as u32;
+		let cells_in_row = (cells_in_page - row * self.columns).min(self.columns);
+		let row_x = match self.last_row {
+			_ if cells_in_row == self.columns => col * cell_step,
+			LastRow::Left => col * cell_step,
+			LastRow::Center => (self.columns - cells_in_row) * cell_step / 2 + col * cell_step,
+			LastRow::Spread => {
+				let row_width = self.columns * cell_step - self.gutter.0;
+				let slot_width = row_width as f32 / cells_in_row as f32;
+				(slot_width * (col as f32 + 0.5) - self.cell_size.0 as f32 / 2.0).round() as u32
+			}
+		};
+
+		let cell_tr = (
+			(output_width - grid_width) / 2 + self.margin.0 + row_x,
+			(output_height - grid_height) / 2 + self.margin.1 + row * (self.cell_size.1 + self.gutter.1),
+		);
+		(cell_tr.0 as i32, cell_tr.1 as i32, self.cell_size.0, self.cell_size.1)
+	}
+}
+
+/// Result of building a whole grid.
+pub struct GridOutput {
+	/// Output image of each page.
+	pub images: Vec<RgbaImage>,
+	pub layout: GridLayout,
+	pub cells: Vec<Cell>,
+	/// Input files that were not used, with the reason.
+	pub skipped: Vec<(PathBuf, Error)>,
+}
+
+/// Progress of building a whole grid with `FaceGrid::run_with_progress()`, reported as it happens.
+pub enum Progress<'a> {
+	/// The faces of an input were found. Only reported with an output fit, where all faces are found before
+	/// aligning them.
+	Detected {
+		index: usize,
+		num_inputs: usize,
+		path: &'a Path,
+		detection: std::result::Result<&'a Detection, &'a Error>,
+	},
+	/// All faces were found, and the cell size was solved for the cells they make.
+	DetectionDone {
+		num_inputs: usize,
+		num_cells: usize,
+	},
+	/// The cells of an input were made, or it was skipped. With an output fit, they're made from its detection,
+	/// without finding its faces again.
+	Aligned {
+		index: usize,
+		num_images: usize,
+		outcome: &'a ImageOutcome,
+		from_detection: bool,
+	},
+	/// There are enough cells for the maximum number of images, so the remaining inputs aren't read.
+	MaxImagesReached,
+	/// All cells were made, before arranging them.
+	AlignmentDone {
+		num_images: usize,
+		num_cells: usize,
+	},
+	/// The cells were arranged, and are about to be painted.
+	Arranged {
+		layout: &'a GridLayout,
+	},
+	/// A cell was painted into the output image of its page.
+	Rendered {
+		index: usize,
+		layout: &'a GridLayout,
+	},
+}
+
+/// Creates a grid of face-aligned images.
+///
+/// The grid can be built in one go with `run()` (or `run_with_progress()`), or stage by stage: `detect()` and `align()` for each
+/// input image (or `process_all()` for many images in parallel), then `layout()` and `render()` for the
+/// cells found. With an output fit, the faces are found first with `detect_for_layout()`, then
+/// `fit_to_output()` solves the cell size, and `align_all()` aligns them.
+pub struct FaceGrid {
+	face_source: Box<dyn FaceSource>,
+	inputs: Vec<PathBuf>,
+	cell_size: WHi,
+	gutter: WHi,
+	margin: WHi,
+	background: Rgba<u8>,
+	face_scale: f32,
+	columns: u32,
+	max_images: u32,
+	min_confidence: f32,
+	min_face_size: Option<Length>,
+	multi_face: MultiFacePolicy,
+	align_mode: AlignMode,
+	eye_distance: f32,
+	eye_height: f32,
+	level_eyes: bool,
+	max_rotation: f32,
+	filter: ResampleFilter,
+	sort: Option<SortOrder>,
+	reverse: bool,
+	seed: Option<u64>,
+	page_size: Option<PageSize>,
+	output_fit: Option<OutputFit>,
+	aspect: f32,
+	last_row: LastRow,
+	jobs: usize,
+}
+
+impl FaceGrid {
+	/// Create a new grid that gets faces from the given source.
+	pub fn new(face_source: Box<dyn FaceSource>) -> Self {
+		Self {
+			face_source,
+			inputs: vec![],
+			cell_size: (100, 100),
+			gutter: (0, 0),
+			margin: (0, 0),
+			background: Rgba([0, 0, 0, 0]),
+			face_scale: 1.0,
+			columns: 0,
+			max_images: 0,
+			min_confidence: 0.0,
+			min_face_size: None,
+			multi_face: MultiFacePolicy::Skip,
+			align_mode: AlignMode::Box,
+			eye_distance: 0.25,
+			eye_height: 0.4,
+			level_eyes: false,
+			max_rotation: 45.0,
+			filter: ResampleFilter::Lanczos3,
+			sort: None,
+			reverse: false,
+			seed: None,
+			page_size: None,
+			output_fit: None,
+			aspect: 1.0,
+			last_row: LastRow::Left,
+			jobs: 0,
+		}
+	}
+
+	/// Sets the input image files, in the order they're placed in the grid.
+	pub fn inputs(mut self, inputs: Vec<PathBuf>) -> Self {
+		self.inputs = inputs;
+		self
+	}
+
+	/// Sets the dimensions of each cell.
+	pub fn cell_size(mut self, cell_size: WHi) -> Self {
+		self.cell_size = cell_size;
+		self
+	}
+
+	/// Sets how cells are split into pages. Without a page size, all cells are in one page.
+	pub fn page_size(mut self, page_size: Option<PageSize>) -> Self {
+		self.page_size = page_size;
+		self
+	}
+
+	/// Sets a size or aspect ratio for the output image of each page. The columns and cell size are solved with
+	/// `fit_to_output()` once the number of cells is known; until then, the cell size only gives their aspect ratio.
+	pub fn output_fit(mut self, output_fit: Option<OutputFit>) -> Self {
+		self.output_fit = output_fit;
+		self
+	}
+
+	/// Sets the horizontal and vertical space between cells.
+	pub fn gutter(mut self, gutter: WHi) -> Self {
+		self.gutter = gutter;
+		self
+	}
+
+	/// Sets the horizontal and vertical space around the grid.
+	pub fn margin(mut self, margin: WHi) -> Self {
+		self.margin = margin;
+		self
+	}
+
+	/// Sets the color of the output image where there are no faces. Transparent by default.
+	pub fn background(mut self, background: Rgba<u8>) -> Self {
+		self.background = background;
+		self
+	}
+
+	/// Sets the scale of the face when aligning by box.
+	pub fn face_scale(mut self, face_scale: f32) -> Self {
+		self.face_scale = face_scale;
+		self
+	}
+
+	/// Sets the number of columns. With 0, the number of columns is chosen to get close to the aspect ratio.
+	pub fn columns(mut self, columns: u32) -> Self {
+		self.columns = columns;
+		self
+	}
+
+	/// Sets the aspect ratio (width / height) of the whole grid that the automatic number of columns aims for.
+	pub fn aspect(mut self, aspect: f32) -> Self {
+		self.aspect = aspect;
+		self
+	}
+
+	/// Sets where the cells of a last row that isn't full are placed.
+	pub fn last_row(mut self, last_row: LastRow) -> Self {
+		self.last_row = last_row;
+		self
+	}
+
+	/// Sets the maximum number of cells. With 0, there is no maximum.
+	pub fn max_images(mut self, max_images: u32) -> Self {
+		self.max_images = max_images;
+		self
+	}
+
+	/// Sets the minimum confidence and size of a face for it to be used.
+	pub fn min_face(mut self, min_confidence: f32, min_face_size: Option<Length>) -> Self {
+		self.min_confidence = min_confidence;
+		self.min_face_size = min_face_size;
+		self
+	}
+
+	/// Sets what to do with images with more than one face.
+	pub fn multi_face(mut self, multi_face: MultiFacePolicy) -> Self {
+		self.multi_face = multi_face;
+		self
+	}
+
+	/// Sets how faces are aligned in their cells.
+	pub fn align_mode(mut self, align_mode: AlignMode) -> Self {
+		self.align_mode = align_mode;
+		self
+	}
+
+	/// Sets where the eyes are placed when aligning by eyes, as fractions of the cell width and height.
+	pub fn eye_position(mut self, eye_distance: f32, eye_height: f32) -> Self {
+		self.eye_distance = eye_distance;
+		self.eye_height = eye_height;
+		self
+	}
+
+	/// Sets whether faces are rotated so their eyes are level, up to a maximum rotation in degrees. Negative or
+	/// NaN maximums don't rotate faces at all.
+	pub fn level_eyes(mut self, level_eyes: bool, max_rotation: f32) -> Self {
+		self.level_eyes = level_eyes;
+		self.max_rotation = max_rotation.max(0.0);
+		self
+	}
+
+	/// Sets the order of the cells in the grid, and whether it's reversed. Without an order, cells follow the
+	/// order of the inputs.
+	pub fn sort(mut self, sort: Option<SortOrder>, reverse: bool) -> Self {
+		self.sort = sort;
+		self.reverse = reverse;
+		self
+	}
+
+	/// Sets the seed of the random order, so it can be repeated. Without one, each shuffle uses a new random seed.
+	pub fn seed(mut self, seed: u64) -> Self {
+		self.seed = Some(seed);
+		self
+	}
+
+	/// Sets the filter used when resampling faces into their cells.
+	pub fn filter(mut self, filter: ResampleFilter) -> Self {
+		self.filter = filter;
+		self
+	}
+
+	/// Sets the number of images processed in parallel. With 0, uses one per CPU core.
+	pub fn jobs(mut self, jobs: usize) -> Self {
+		self.jobs = jobs;
+		self
+	}
+
+	/// Where the face will be in each cell, when aligning by box.
+	fn target_faces_rect(&self) -> WHf {
+		let typical_face_size: WHf = (75f32, 100f32); // Typically 0.75 aspect ratio
+		let faces_rect_inside =
+			fit_inside((self.cell_size.0 as f32, self.cell_size.1 as f32), typical_face_size);
+		let typical_face_scale = 0.6f32 * self.face_scale;
+		(faces_rect_inside.0 * typical_face_scale, faces_rect_inside.1 * typical_face_scale)
+	}
+
+	/// Reads an image and finds the faces to use from it, returning the upright image and what was found.
+	pub fn detect(&self, path: &Path) -> Result<(RgbImage, Detection)> {
+		let (image, original_size, orientation) = open_upright(path)?;
+		let faces = self.face_source.find_faces(path, &image)?;
+		let num_faces_found = faces.len();
+		let faces = filter_faces(faces, self.min_confidence, self.min_face_size, image.dimensions());
+		let num_faces_accepted = faces.len();
+		let faces = select_faces(faces, self.multi_face, image.dimensions());
+
+		let detection = Detection {
+			path: path.to_path_buf(),
+			original_size,
+			size: image.dimensions(),
+			orientation,
+			num_faces_found,
+			num_faces_accepted,
+			faces,
+		};
+		Ok((image, detection))
+	}
+
+	/// Where a face is placed in its cell.
+	fn face_transform(&self, face: &Face) -> FaceTransform {
+		let mut transform = match self.align_mode {
+			AlignMode::Box => None,
+			AlignMode::Eyes => {
+				align::eyes_transform(face, self.eye_distance, self.eye_height, self.cell_size)
+			}
+		}
+		.unwrap_or_else(|| align::box_transform(face, self.target_faces_rect(), self.cell_size));
+
+		// Level the eyes, if needed
+		let max_rotation = self.max_rotation.to_radians();
+		transform.angle = align::eye_angle(face)
+			.filter(|_| self.level_eyes)
+			.map_or(0.0, |angle| angle.clamp(-max_rotation, max_rotation));
+		transform
+	}
+
+	/// Checks that a detection will make cells when aligned, without rendering them.
+	fn check_cells(&self, detection: Detection) -> Result<Detection> {
+		if detection.faces.is_empty() {
+			return Err(Error::NoFaces);
+		}
+		let is_visible = |face| align::is_visible(detection.size, &self.face_transform(face), self.cell_size);
+		if !detection.faces.iter().all(is_visible) {
+			return Err(Error::OutsideCell);
+		}
+		Ok(detection)
+	}
+
+	/// Aligns and renders each face of a detection for its cell.
+	pub fn align(&self, image: &RgbImage, detection: &Detection) -> Result<Vec<Cell>> {
+		if detection.faces.is_empty() {
+			return Err(Error::NoFaces);
+		}
+
+		let mut cells = vec![];
+		for face in &detection.faces {
+			let transform = self.face_transform(face);
+			let (image, offset) = align::render_face(image, &transform, self.cell_size, self.filter)
+				.ok_or(Error::OutsideCell)?;
+			cells.push(Cell {
+				path: detection.path.clone(),
+				face: face.clone(),
+				transform,
+				image,
+				offset,
+			});
+		}
+
+		Ok(cells)
+	}
+
+	/// Detects and aligns the faces of one image.
+	pub fn process(&self, path: &Path) -> ImageOutcome {
+		match self.detect(path) {
+			Ok((image, detection)) => {
+				let cells = self.align(&image, &detection);
+				ImageOutcome {
+					path: path.to_path_buf(),
+					detection: Some(detection),
+					cells,
+				}
+			}
+			Err(err) => ImageOutcome {
+				path: path.to_path_buf(),
+				detection: None,
+				cells: Err(err),
+			},
+		}
+	}
+
+	/// Aligns the faces of an image from an earlier detection, reading the image again but without finding its
+	/// faces again.
+	pub fn realign(&self, detection: &Detection) -> ImageOutcome {
+		let cells = open_upright(&detection.path).and_then(|(image, _, _)| self.align(&image, detection));
+		ImageOutcome {
+			path: detection.path.clone(),
+			detection: Some(detection.clone()),
+			cells,
+		}
+	}
+
+	/// Processes many images in parallel. The outcomes are reported in the same order as the paths,
+	/// until `on_outcome` returns false. A fatal error (e.g. the model failing to load) stops, and is returned.
+	pub fn process_all<F: FnMut(usize, ImageOutcome) -> bool>(
+		&self,
+		paths: &[PathBuf],
+		on_outcome: F,
+	) -> Result<()> {
+		self.report_outcomes(paths, |path| self.process(path), on_outcome)
+	}
+
+	/// Aligns the faces of earlier detections in parallel, like `process_all()` but without finding them again.
+	pub fn align_all<F: FnMut(usize, ImageOutcome) -> bool>(
+		&self,
+		detections: &[Detection],
+		on_outcome: F,
+	) -> Result<()> {
+		self.report_outcomes(detections, |detection| self.realign(detection), on_outcome)
+	}
+
+	/// Runs a task that makes the outcome of an image for each item in parallel, reporting them in order until
+	/// `on_outcome` returns false, or stopping at a fatal error.
+	fn report_outcomes<'a, I: Sync, P, F>(&self, items: &'a [I], task: P, mut on_outcome: F) -> Result<()>
+	where
+		P: Fn(&'a I) -> ImageOutcome + Sync,
+		F: FnMut(usize, ImageOutcome) -> bool,
+	{
+		let mut fatal = None;
+		self.for_each_parallel(items, task, |index, outcome| match outcome.cells {
+			Err(err) if err.is_fatal() => {
+				fatal = Some(err);
+				false
+			}
+			cells => on_outcome(
+				index,
+				ImageOutcome {
+					cells,
+					..outcome
+				},
+			),
+		})?;
+		fatal.map_or(Ok(()), Err)
+	}
+
+	/// Finds the faces of many images in parallel, without aligning them. The detections are reported in the
+	/// same order as the paths, until `on_detection` returns false or a fatal error is returned.
+	pub fn detect_all<F: FnMut(usize, &Path, Result<Detection>) -> bool>(
+		&self,
+		paths: &[PathBuf],
+		mut on_detection: F,
+	) -> Result<()> {
+		let mut fatal = None;
+		self.for_each_parallel(
+			paths,
+			|path| (path, self.detect(path).map(|(_, detection)| detection)),
+			|index, (path, detection)| match detection {
+				Err(err) if err.is_fatal() => {
+					fatal = Some(err);
+					false
+				}
+				detection => on_detection(index, path, detection),
+			},
+		)?;
+		fatal.map_or(Ok(()), Err)
+	}
+
+	/// Finds the faces of many images in parallel, checking that they'll make cells, so the layout can be solved
+	/// before aligning them with `align_all()`. The detections are reported in the same order as the paths, and
+	/// the usable ones are returned. Without sorting, stops once there are enough cells for the maximum number
+	/// of images.
+	pub fn detect_for_layout<F: FnMut(usize, &Path, Result<&Detection>)>(
+		&self,
+		paths: &[PathBuf],
+		mut on_detection: F,
+	) -> Result<Vec<Detection>> {
+		let mut detections = vec![];
+		self.detect_all(paths, |index, path, detection| {
+			match detection.and_then(|detection| self.check_cells(detection)) {
+				Ok(detection) => {
+					on_detection(index, path, Ok(&detection));
+					detections.push(detection);
+				}
+				Err(err) => on_detection(index, path, Err(err)),
+			}
+			self.reorders_cells()
+				|| !(self.max_images > 0 && self.count_cells(&detections) >= self.max_images as usize)
+		})?;
+		Ok(detections)
+	}
+
+	/// Number of cells that detections make, up to the maximum number of images.
+	pub fn count_cells(&self, detections: &[Detection]) -> usize {
+		let num_cells = detections.iter().map(|detection| detection.faces.len()).sum::<usize>();
+		match self.max_images {
+			0 => num_cells,
+			max_images => num_cells.min(max_images as usize),
+		}
+	}
+
+	/// Runs a task for each item in parallel, reporting the results in order as soon as they're ready, until
+	/// `on_result` returns false.
+	fn for_each_parallel<'a, I: Sync, T: Send, P, F>(
+		&self,
+		items: &'a [I],
+		task: P,
+		mut on_result: F,
+	) -> Result<()>
+	where
+		P: Fn(&'a I) -> T + Sync,
+		F: FnMut(usize, T) -> bool,
+	{
+		let pool = rayon::ThreadPoolBuilder::new()
+			.num_threads(self.jobs)
+			.build()
+			.map_err(|err| Error::WorkerThreads(err.to_string()))?;
+
+		// Each worker takes the next item when it's done with the previous one, so a slow image only holds up
+		// its own worker
+		let next_index = &AtomicUsize::new(0);
+		let stop = &AtomicBool::new(false);
+		let task = &task;
+		let (sender, receiver) = mpsc::channel::<(usize, T)>();
+		pool.in_place_scope(|scope| {
+			for _ in 0..pool.current_num_threads() {
+				let sender = sender.clone();
+				scope.spawn(move |_| {
+					while !stop.load(Ordering::Relaxed) {
+						let index = next_index.fetch_add(1, Ordering::Relaxed);
+						let Some(item) = items.get(index) else {
+							break;
+						};
+						if sender.send((index, task(item))).is_err() {
+							break;
+						}
+					}
+				});
+			}
+			drop(sender);
+
+			// Results that arrive early are kept until it's their turn
+			let mut pending = HashMap::new();
+			let mut next_to_report = 0;
+			for (index, result) in receiver {
+				pending.insert(index, result);
+				while let Some(result) = pending.remove(&next_to_report) {
+					if !on_result(next_to_report, result) {
+						stop.store(true, Ordering::Relaxed);
+						return;
+					}
+					next_to_report += 1;
+				}
+			}
+		});
+		Ok(())
+	}
+
+	/// Puts cells in the order they're placed in the grid.
+	pub fn sort_cells(&self, cells: &mut [Cell]) {
+		match self.sort {
+			Some(SortOrder::Random) => {
+				let seed = self.seed.unwrap_or_else(sort::random_seed);
+				sort::sort_cells(cells, SortOrder::Random, self.reverse, seed)
+			}
+			Some(order) => sort::sort_cells(cells, order, self.reverse, 0),
+			None if self.reverse => cells.reverse(),
+			None => (),
+		}
+	}
+
+	/// Whether the cells are placed in a different order than the inputs. If so, all images have to be read
+	/// before knowing which ones are kept by the maximum number of images.
+	pub fn reorders_cells(&self) -> bool {
+		self.sort.is_some() || self.reverse
+	}
+
+	/// Puts cells in the order they're placed in the grid, keeping the first ones up to the maximum number of
+	/// images.
+	pub fn arrange_cells(&self, cells: &mut Vec<Cell>) {
+		self.sort_cells(cells);
+		if self.max_images > 0 {
+			cells.truncate(self.max_images as usize);
+		}
+	}
+
+	/// Solves the columns and cell size that best fill the output fit with a number of cells, keeping the aspect
+	/// ratio of the cells. Without an output fit, nothing changes. Fails if the cells can't fit in the output size,
+	/// e.g. because the gutters and margins take all the space.
+	pub fn fit_to_output(mut self, num_cells: usize) -> Result<Self> {
+		let Some(output_fit) = self.output_fit else {
+			return Ok(self);
+		};
+		let num_cells = match self.page_size {
+			Some(PageSize::Cells(cells_per_page)) => cells_per_page.min(num_cells),
+			_ => num_cells,
+		}
+		.max(1);
+
+		// Tries every column count, keeping the one where the cells cover the most of the output
+		let mut best: Option<(f32, u32, WHi)> = None;
+		for columns in 1..=num_cells as u32 {
+			let rows = num_cells.div_ceil(columns as usize) as u32;
+			let spacing = (
+				(columns - 1) * self.gutter.0 + self.margin.0 * 2,
+				(rows - 1) * self.gutter.1 + self.margin.1 * 2,
+			);
+			let cell_size = match output_fit {
+				OutputFit::Size(size) => {
+					let available =
+						(size.0.saturating_sub(spacing.0) as f32, size.1.saturating_sub(spacing.1) as f32);
+					let cells_rect = ((columns * self.cell_size.0) as f32, (rows * self.cell_size.1) as f32);
+					let (cells_width, cells_height) = fit_inside(available, cells_rect);
+					((cells_width / columns as f32) as u32, (cells_height / rows as f32) as u32)
+				}
+				OutputFit::Aspect(_) => self.cell_size,
+			};
+			if cell_size.0 == 0 || cell_size.1 == 0 {
+				continue;
+			}
+
+			let grid_size = (columns * cell_size.0 + spacing.0, rows * cell_size.1 + spacing.1);
+			let output_size = output_fit.output_size(grid_size);
+			let coverage = (num_cells as f32 * cell_size.0 as f32 * cell_size.1 as f32)
+				/ (output_size.0 as f32 * output_size.1 as f32);
+			if best.is_none_or(|(best_coverage, _, _)| coverage > best_coverage) {
+				best = Some((coverage, columns, cell_size));
+			}
+		}
+
+		let Some((_, columns, cell_size)) = best else {
+			let OutputFit::Size(size) = output_fit else {
+				return Err(Error::InvalidOption("the cell size can't be empty".to_string()));
+			};
+			return Err(Error::InvalidOption(format!(
+				"{} cells don't fit in an output of {}x{} with the gutter and margin given",
+				num_cells, size.0, size.1
+			)));
+		};
+		self.columns = columns;
+		self.cell_size = cell_size;
+		Ok(self)
+	}
+
+	/// Decides the arrangement of a number of cells, and how many pages they need.
+	pub fn layout(&self, num_cells: usize) -> GridLayout {
+		let num_cells = num_cells.max(1);
+		let auto_columns_cells = match self.page_size {
+			Some(PageSize::Cells(cells_per_page)) => cells_per_page.clamp(1, num_cells),
+			_ => num_cells,
+		};
+		let columns = if self.columns == 0 {
+			self.auto_columns(auto_columns_cells)
+		} else {
+			self.columns
+		};
+		let cells_per_page = match self.page_size {
+			None => num_cells,
+			Some(PageSize::Cells(cells_per_page)) => cells_per_page.clamp(1, num_cells),
+			Some(PageSize::Rows(rows)) => (rows.max(1) as usize * columns as usize).min(num_cells),
+		};
+		let rows = (cells_per_page as f32 / columns as f32).ceil() as u32;
+		GridLayout {
+			columns,
+			rows,
+			cell_size: self.cell_size,
+			gutter: self.gutter,
+			margin: self.margin,
+			cells_per_page,
+			pages: num_cells.div_ceil(cells_per_page),
+			output_fit: self.output_fit,
+			num_cells,
+			last_row: self.last_row,
+		}
+	}
+
+	/// Chooses the number of columns for a number of cells: among the ones close enough to the aspect ratio,
+	/// the one that leaves the fewest empty cells. Ties go to more columns, so grids lean to landscape.
+	fn auto_columns(&self, num_cells: usize) -> u32 {
+		let candidates = (1..=num_cells as u32)
+			.map(|columns| {
+				let rows = num_cells.div_ceil(columns as usize) as u32;
+				let width = columns * self.cell_size.0 + (columns - 1) * self.gutter.0 + self.margin.0 * 2;
+				let height = rows * self.cell_size.1 + (rows - 1) * self.gutter.1 + self.margin.1 * 2;
+				let aspect_error = (width as f32 / height.max(1) as f32 / self.aspect).ln().abs();
+				let empty_cells = rows as usize * columns as usize - num_cells;
+				(columns, aspect_error, empty_cells)
+			})
+			.collect::<Vec<(u32, f32, usize)>>();
+
+		let best_aspect_error =
+			candidates.iter().map(|&(_, aspect_error, _)| aspect_error).fold(f32::MAX, f32::min);
+		candidates
+			.into_iter()
+			.filter(|&(_, aspect_error, _)| aspect_error <= best_aspect_error + ASPECT_TOLERANCE.ln())
+			.min_by(|a, b| {
+				// Mirrored grids (e.g. 2x3 and 3x2) are as far from a square, but only up to rounding
+				let aspect_order = if (a.1 - b.1).abs() < 1e-4 {
+					std::cmp::Ordering::Equal
+				} else {
+					a.1.total_cmp(&b.1)
+				};
+				a.2.cmp(&b.2).then(aspect_order).then(b.0.cmp(&a.0))
+			})
+			.map_or(1, |(columns, _, _)| columns)
+	}
+
+	/// Creates an empty output image for a layout.
+	pub fn new_canvas(&self, layout: &GridLayout) -> RgbaImage {
+		let (output_width, output_height) = layout.output_size();
+		ImageBuffer::from_pixel(output_width, output_height, self.background)
+	}
+
+	/// Paints one cell into the output image of its page.
+	pub fn render_cell(&self, canvas: &mut RgbaImage, layout: &GridLayout, index: usize, cell: &Cell) {
+		copy_image(canvas, &cell.image, cell.offset, layout.cell_rect(index));
+	}
+
+	/// Paints all cells into new output images, one per page.
+	pub fn render(&self, cells: &[Cell], layout: &GridLayout) -> Vec<RgbaImage> {
+		self.render_with_progress(cells, layout, |_| ())
+	}
+
+	/// Paints all cells like `render()`, reporting the index of each cell once it's painted.
+	fn render_with_progress<F: FnMut(usize)>(
+		&self,
+		cells: &[Cell],
+		layout: &GridLayout,
+		mut on_cell: F,
+	) -> Vec<RgbaImage> {
+		let mut canvases = vec![];
+		for (page, page_cells) in cells.chunks(layout.cells_per_page).enumerate() {
+			let mut canvas = self.new_canvas(layout);
+			for (index, cell) in page_cells.iter().enumerate() {
+				let index = page * layout.cells_per_page + index;
+				self.render_cell(&mut canvas, layout, index, cell);
+				on_cell(index);
+			}
+			canvases.push(canvas);
+		}
+		canvases
+	}
+
+	/// Builds the whole grid from the inputs. Images that can't be used are skipped.
+	pub fn run(self) -> Result<GridOutput> {
+		self.run_with_progress(|_| ())
+	}
+
+	/// Builds the whole grid from the inputs like `run()`, reporting the progress of each stage.
+	pub fn run_with_progress<F: FnMut(Progress)>(self, mut on_progress: F) -> Result<GridOutput> {
+		// With an output fit, the number of cells has to be known before aligning them, so the faces are found
+		// first, and aligned from those detections
+		let mut skipped = vec![];
+		let (grid, detections) = if self.output_fit.is_some() {
+			let num_inputs = self.inputs.len();
+			let detections = self.detect_for_layout(&self.inputs, |index, path, detection| {
+				on_progress(Progress::Detected {
+					index,
+					num_inputs,
+					path,
+					detection: detection.as_ref().copied(),
+				});
+				if let Err(err) = detection {
+					skipped.push((path.to_path_buf(), err));
+				}
+			})?;
+			let num_cells = self.count_cells(&detections);
+			on_progress(Progress::DetectionDone {
+				num_inputs,
+				num_cells,
+			});
+			(self.fit_to_output(num_cells)?, Some(detections))
+		} else {
+			(self, None)
+		};
+		grid.build(detections.as_deref(), skipped, on_progress)
+	}
+
+	fn build<F: FnMut(Progress)>(
+		&self,
+		detections: Option<&[Detection]>,
+		mut skipped: Vec<(PathBuf, Error)>,
+		mut on_progress: F,
+	) -> Result<GridOutput> {
+		let mut cells = vec![];
+		let num_images = detections.map_or(self.inputs.len(), <[Detection]>::len);
+		let on_outcome = |index, outcome: ImageOutcome| {
+			on_progress(Progress::Aligned {
+				index,
+				num_images,
+				outcome: &outcome,
+				from_detection: detections.is_some(),
+			});
+			match outcome.cells {
+				Ok(image_cells) => cells.extend(image_cells),
+				Err(err) => skipped.push((outcome.path, err)),
+			}
+			// Without sorting, the first cells are the ones kept, so the remaining images don't need to be read
+			let is_done =
+				!self.reorders_cells() && self.max_images > 0 && cells.len() >= self.max_images as usize;
+			if is_done {
+				on_progress(Progress::MaxImagesReached);
+			}
+			!is_done
+		};
+		match detections {
+			Some(detections) => self.align_all(detections, on_outcome)?,
+			None => self.process_all(&self.inputs, on_outcome)?,
+		}
+		on_progress(Progress::AlignmentDone {
+			num_images,
+			num_cells: cells.len(),
+		});
+
+		if cells.is_empty() {
+			return Err(Error::NoResults);
+		}
+
+		self.arrange_cells(&mut cells);
+		let layout = self.layout(cells.len());
+		on_progress(Progress::Arranged {
+			layout: &layout,
+		});
+		let images = self.render_with_progress(&cells, &layout, |index| {
+			on_progress(Progress::Rendered {
+				index,
+				layout: &layout,
+			})
+		});
+		Ok(GridOutput {
+			images,
+			layout,
+			cells,
+			skipped,
+		})
+	}
+}
+
+/**
+ * Read an image and make it upright, returning it with its size as stored in the file and the EXIF orientation
+ * applied, if any
+ */
+fn open_upright(path: &Path) -> Result<(RgbImage, WHi, Option<u16>)> {
+	let img = image::open(path)?;
+	let original_size = (img.width(), img.height());
+	let orientation = exif::read_orientation(path).filter(|&orientation| orientation != 1);
+	let img = match orientation {
+		Some(orientation) => exif::apply_orientation(img, orientation),
+		None => img,
+	};
+	Ok((img.into_rgb8(), original_size, orientation))
+}
+
+/**
+ * Paint one image on top of another, blending the pixels that are partly transparent (e.g. the edges of rotated
+ * faces) over the bottom image
+ */
+fn copy_image(bottom: &mut RgbaImage, top: &RgbaImage, cell_top_offset: XYi, cell: XYWHi) {
+	// Find paintable intersection between bottom and top
+	let bottom_rect = (0, 0, cell.2, cell.3);
+	let top_rect = (cell_top_offset.0, cell_top_offset.1, top.width(), top.height());
+	let Some(intersection_rect) = intersect(bottom_rect, top_rect) else {
+		// Nothing to paint
+		return;
+	};
+
+	let dst_x1 = intersection_rect.0 + cell.0;
+	let dst_y1 = intersection_rect.1 + cell.1;
+	let dst_x2 = intersection_rect.0 + intersection_rect.2 as i32 + cell.0;
+	let dst_y2 = intersection_rect.1 + intersection_rect.3 as i32 + cell.1;
+
+	for dst_y in dst_y1..dst_y2 {
+		let src_y = (dst_y - cell_top_offset.1 - cell.1) as u32;
+		for dst_x in dst_x1..dst_x2 {
+			let src_x = (dst_x - cell_top_offset.0 - cell.0) as u32;
+			blend_over(bottom.get_pixel_mut(dst_x as u32, dst_y as u32), top.get_pixel(src_x, src_y));
+		}
+	}
+}
+
+/**
+ * Composite a pixel over another one ("source over"). Unlike `Pixel::blend`, an opaque bottom pixel stays opaque.
+ */
+fn blend_over(bottom: &mut Rgba<u8>, top: &Rgba<u8>) {
+	match top.0[3] {
+		0 => (),
+		255 => *bottom = *top,
+		top_alpha => {
+			let top_alpha = top_alpha as f32 / 255.0;
+			let bottom_alpha = bottom.0[3] as f32 / 255.0 * (1.0 - top_alpha);
+			let alpha = top_alpha + bottom_alpha;
+			for c in 0..3 {
+				let value = (top.0[c] as f32 * top_alpha + bottom.0[c] as f32 * bottom_alpha) / alpha;
+				bottom.0[c] = value.round() as u8;
+			}
+			bottom.0[3] = (alpha * 255.0).round() as u8;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::sync::Arc;
+	use std::thread;
+	use std::time::Duration;
+
+	use super::*;
+	use crate::faces::FixedFaceSource;
+	use crate::faces::tests::CountingFaceSource;
+	use crate::sort::tests::{cell, paths as paths_of};
+
+	fn grid() -> FaceGrid {
+		FaceGrid::new(Box::new(FixedFaceSource {
+			rect: (0.0, 0.0, 10.0, 10.0),
+		}))
+	}
+
+	fn paths(count: usize) -> Vec<PathBuf> {
+		(0..count).map(|index| PathBuf::from(format!("{}.jpg", index))).collect()
+	}
+
+	#[test]
+	fn eye_rotation_is_clamped_to_the_maximum() {
+		// The right eye is 45 degrees below the left one
+		let face = Face {
+			rect: (0.0, 0.0, 100.0, 100.0),
+			landmarks: vec![(30.0, 40.0), (70.0, 80.0)],
+			confidence: 1.0,
+		};
+		let angle = |grid: FaceGrid| grid.face_transform(&face).angle.to_degrees();
+		assert!((angle(grid().level_eyes(true, 90.0)) - 45.0).abs() < 1e-4);
+		assert!((angle(grid().level_eyes(true, 10.0)) - 10.0).abs() < 1e-4);
+		assert_eq!(angle(grid().level_eyes(false, 90.0)), 0.0);
+		assert_eq!(angle(grid().level_eyes(true, f32::NAN)), 0.0);
+		assert_eq!(angle(grid().level_eyes(true, -10.0)), 0.0);
+	}
+
+	#[test]
+	fn faces_without_eyes_are_aligned_by_their_box() {
+		let face = Face {
+			rect: (10.0, 20.0, 30.0, 40.0),
+			landmarks: vec![],
+			confidence: 1.0,
+		};
+		let boxed = grid().face_transform(&face);
+		let eyes = grid().align_mode(AlignMode::Eyes).level_eyes(true, 45.0).face_transform(&face);
+		assert_eq!(eyes, boxed);
+		assert_eq!(eyes.source_anchor, (25.0, 40.0));
+		assert_eq!(eyes.angle, 0.0);
+	}
+
+	#[test]
+	fn parallel_results_are_reported_in_order() {
+		let grid = grid().jobs(4);
+		let paths = paths(20);
+		let mut reported = vec![];
+		grid.for_each_parallel(
+			&paths,
+			|path| {
+				// The first image is much slower than the rest
+				if path == Path::new("0.jpg") {
+					thread::sleep(Duration::from_millis(50));
+				}
+				path.clone()
+			},
+			|index, path| {
+				reported.push((index, path));
+				true
+			},
+		)
+		.unwrap();
+		assert_eq!(reported, paths.into_iter().enumerate().collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn parallel_processing_stops_early() {
+		let grid = grid().jobs(2);
+		let paths = paths(100);
+		let mut reported = vec![];
+		grid.for_each_parallel(
+			&paths,
+			|path| path.clone(),
+			|index, _| {
+				reported.push(index);
+				index < 4
+			},
+		)
+		.unwrap();
+		assert_eq!(reported, vec![0, 1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn cells_only_keep_the_visible_part_of_the_face() {
+		let dir = std::env::temp_dir().join(format!("face-grid-cells-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("large.png");
+		RgbImage::from_pixel(400, 300, image::Rgb([200, 100, 50])).save(&path).unwrap();
+
+		let grid = FaceGrid::new(Box::new(FixedFaceSource {
+			rect: (100.0, 100.0, 50.0, 50.0),
+		}))
+		.cell_size((20, 10));
+		let cells = grid.process(&path).cells.unwrap();
+		assert_eq!(cells.len(), 1);
+		let cell = &cells[0];
+		assert!(cell.image.width() <= 20 && cell.image.height() <= 10, "{:?}", cell.image.dimensions());
+		assert_eq!(cell.offset, (0, 0));
+		assert_eq!(cell.image.get_pixel(0, 0).0, [200, 100, 50, 255]);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn copied_images_are_clipped_to_their_cell() {
+		let mut canvas = RgbaImage::new(10, 10);
+		let mut top = RgbaImage::from_pixel(6, 6, Rgba([255, 0, 0, 255]));
+		top.put_pixel(3, 3, Rgba([0, 0, 0, 0]));
+
+		// The cell is at (2, 2) with a 4x4 size; the image starts 1 pixel before it
+		copy_image(&mut canvas, &top, (-1, -1), (2, 2, 4, 4));
+		let painted = canvas.enumerate_pixels().filter(|(_, _, pixel)| pixel.0[3] > 0).count();
+		assert_eq!(painted, 4 * 4 - 1);
+		assert_eq!(canvas.get_pixel(2, 2).0, [255, 0, 0, 255]);
+		assert_eq!(canvas.get_pixel(5, 5).0, [255, 0, 0, 255]);
+		assert_eq!(canvas.get_pixel(6, 6).0, [0, 0, 0, 0]);
+		// Transparent pixels leave the background
+		assert_eq!(canvas.get_pixel(4, 4).0, [0, 0, 0, 0]);
+
+		// Partly transparent pixels are blended over the background
+		let mut canvas = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+		top.put_pixel(3, 3, Rgba([255, 0, 0, 128]));
+		copy_image(&mut canvas, &top, (-1, -1), (0, 0, 4, 4));
+		let [red, green, blue, alpha] = canvas.get_pixel(2, 2).0;
+		assert_eq!((red, alpha), (255, 255));
+		assert_eq!((green, blue), (127, 127));
+		assert_eq!(canvas.get_pixel(1, 1).0, [255, 0, 0, 255]);
+	}
+
+	#[test]
+	fn layouts_include_gutters_and_margins() {
+		let layout = grid().cell_size((10, 20)).gutter((2, 3)).margin((4, 5)).columns(3).layout(5);
+		assert_eq!((layout.columns, layout.rows), (3, 2));
+		assert_eq!(layout.output_size(), (3 * 10 + 2 * 2 + 4 * 2, 2 * 20 + 3 + 5 * 2));
+		assert_eq!(layout.cell_rect(0), (4, 5, 10, 20));
+		assert_eq!(layout.cell_rect(2), (4 + 2 * 12, 5, 10, 20));
+		assert_eq!(layout.cell_rect(4), (4 + 12, 5 + 23, 10, 20));
+	}
+
+	#[test]
+	fn single_cells_have_no_gutters() {
+		let layout = grid().cell_size((10, 20)).gutter((2, 3)).margin((1, 1)).layout(1);
+		assert_eq!(layout.output_size(), (12, 22));
+		assert_eq!(layout.cell_rect(0), (1, 1, 10, 20));
+	}
+
+	#[test]
+	fn the_maximum_number_of_images_is_applied_after_sorting() {
+		let cells = || vec![cell("a.jpg", 0.5), cell("b.jpg", 0.9), cell("c.jpg", 0.7)];
+
+		let sorted_grid = grid().sort(Some(SortOrder::Confidence), true).max_images(2);
+		assert!(sorted_grid.reorders_cells());
+		let mut sorted = cells();
+		sorted_grid.arrange_cells(&mut sorted);
+		assert_eq!(paths_of(&sorted), ["b.jpg", "c.jpg"]);
+
+		let unsorted_grid = grid().max_images(2);
+		assert!(!unsorted_grid.reorders_cells());
+		let mut unsorted = cells();
+		unsorted_grid.arrange_cells(&mut unsorted);
+		assert_eq!(paths_of(&unsorted), ["a.jpg", "b.jpg"]);
+	}
+
+	#[test]
+	fn fits_cells_to_an_output_size() {
+		let grid =
+			grid().cell_size((10, 10)).output_fit(Some(OutputFit::Size((100, 50)))).fit_to_output(8).unwrap();
+		let layout = grid.layout(8);
+		assert_eq!((layout.columns, layout.rows, layout.cell_size), (4, 2, (25, 25)));
+		assert_eq!(layout.output_size(), (100, 50));
+	}
+
+	#[test]
+	fn fits_cells_to_an_output_aspect_ratio() {
+		let grid =
+			grid().cell_size((10, 10)).output_fit(Some(OutputFit::Aspect(2.0))).fit_to_output(8).unwrap();
+		let layout = grid.layout(8);
+		assert_eq!((layout.columns, layout.rows, layout.cell_size), (4, 2, (10, 10)));
+		assert_eq!(layout.output_size(), (40, 20));
+
+		// With 7 cells, the last row isn't full, but the output keeps the aspect ratio
+		let layout = grid.layout(7);
+		assert_eq!(layout.output_size(), (40, 20));
+	}
+
+	#[test]
+	fn output_sizes_that_cant_fit_the_cells_are_rejected() {
+		let grid = grid().margin((20, 20)).output_fit(Some(OutputFit::Size((30, 30))));
+		assert!(matches!(grid.fit_to_output(7), Err(Error::InvalidOption(_))));
+	}
+
+	#[test]
+	fn faces_are_only_found_once_with_an_output_fit() {
+		let dir = std::env::temp_dir().join(format!("face-grid-fit-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let inputs = ["a.png", "b.png", "c.png"].map(|name| dir.join(name));
+		for input in &inputs[..2] {
+			RgbImage::new(200, 200).save(input).unwrap();
+		}
+		// The face is outside of the small image, so it makes no cell
+		RgbImage::new(20, 20).save(&inputs[2]).unwrap();
+
+		let calls = Arc::new(AtomicUsize::new(0));
+		let source = CountingFaceSource::new((100.0, 100.0, 50.0, 50.0), &calls);
+		let output = FaceGrid::new(Box::new(source))
+			.inputs(inputs.to_vec())
+			.output_fit(Some(OutputFit::Size((100, 40))))
+			.run()
+			.unwrap();
+		assert_eq!(calls.load(Ordering::Relaxed), 3);
+		assert_eq!(output.cells.len(), 2);
+		assert_eq!((output.layout.columns, output.layout.cell_size), (2, (40, 40)));
+		assert_eq!(output.images[0].dimensions(), (100, 40));
+		assert!(matches!(output.skipped.as_slice(), [(path, Error::OutsideCell)] if *path == inputs[2]));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn progress_is_reported_for_each_stage() {
+		let dir = std::env::temp_dir().join(format!("face-grid-progress-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let inputs = ["a.png", "b.png", "c.png"].map(|name| dir.join(name));
+		RgbImage::new(200, 200).save(&inputs[0]).unwrap();
+		RgbImage::new(20, 20).save(&inputs[1]).unwrap();
+		RgbImage::new(200, 200).save(&inputs[2]).unwrap();
+		let grid = || {
+			FaceGrid::new(Box::new(FixedFaceSource {
+				rect: (100.0, 100.0, 50.0, 50.0),
+			}))
+			.inputs(inputs.to_vec())
+		};
+		let describe = |progress: Progress| match progress {
+			Progress::Detected {
+				index,
+				detection,
+				..
+			} => format!("detected {} {}", index, detection.is_ok()),
+			Progress::DetectionDone {
+				num_inputs,
+				num_cells,
+			} => format!("detection done {} {}", num_inputs, num_cells),
+			Progress::Aligned {
+				index,
+				outcome,
+				from_detection,
+				..
+			} => format!("aligned {} {} {}", index, outcome.cells.is_ok(), from_detection),
+			Progress::MaxImagesReached => "max images".to_string(),
+			Progress::AlignmentDone {
+				num_images,
+				num_cells,
+			} => format!("alignment done {} {}", num_images, num_cells),
+			Progress::Arranged {
+				layout,
+			} => format!("arranged {}", layout.num_cells),
+			Progress::Rendered {
+				index,
+				..
+			} => format!("rendered {}", index),
+		};
+
+		let mut reported = vec![];
+		grid()
+			.output_fit(Some(OutputFit::Aspect(1.0)))
+			.run_with_progress(|progress| reported.push(describe(progress)))
+			.unwrap();
+		let expected = [
+			"detected 0 true",
+			"detected 1 false",
+			"detected 2 true",
+			"detection done 3 2",
+			"aligned 0 true true",
+			"aligned 1 true true",
+			"alignment done 2 2",
+			"arranged 2",
+			"rendered 0",
+			"rendered 1",
+		];
+		assert_eq!(reported, expected);
+
+		let mut reported = vec![];
+		grid().max_images(1).run_with_progress(|progress| reported.push(describe(progress))).unwrap();
+		let expected =
+			["aligned 0 true false", "max images", "alignment done 3 1", "arranged 1", "rendered 0"];
+		assert_eq!(reported, expected);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn auto_columns_keep_close_to_the_aspect_ratio() {
+		// 5 columns would leave no empty cells, but they're too far from a square grid; 2x3 and 3x2 are as close,
+		// and the wider one is used
+		assert_eq!(grid().cell_size((10, 10)).auto_columns(5), 3);
+		assert_eq!(grid().cell_size((10, 10)).auto_columns(6), 3);
+		assert_eq!(grid().cell_size((10, 10)).auto_columns(2), 2);
+		assert_eq!(grid().cell_size((10, 10)).auto_columns(9), 3);
+		assert_eq!(grid().cell_size((10, 20)).aspect(2.0).auto_columns(2), 2);
+		assert_eq!(grid().cell_size((10, 10)).auto_columns(1), 1);
+	}
+
+	#[test]
+	fn auto_columns_prefer_fewer_empty_cells_within_the_tolerance() {
+		// 5 columns are the closest to 3:2, but leave 3 empty cells; 4 columns are close enough, and leave none
+		assert_eq!(grid().cell_size((10, 10)).aspect(1.5).auto_columns(12), 4);
+		assert_eq!(grid().cell_size((10, 10)).aspect(1.5).auto_columns(11), 4);
+	}
+
+	#[test]
+	fn last_rows_can_be_stretched_as_an_alias_of_spread() {
+		assert_eq!("stretch".parse::<LastRow>(), Ok(LastRow::Spread));
+		assert_eq!("Spread".parse::<LastRow>(), Ok(LastRow::Spread));
+		assert_eq!(LastRow::Spread.to_string(), "spread");
+		for name in LastRow::NAMES {
+			assert!(name.parse::<LastRow>().is_ok(), "{name}");
+		}
+	}
+
+	#[test]
+	fn last_rows_are_placed_as_requested() {
+		let positions = |last_row, num_cells| {
+			let layout =
+				grid().cell_size((10, 10)).gutter((2, 0)).columns(3).last_row(last_row).layout(num_cells);
+			(3..num_cells).map(|index| layout.cell_rect(index).0).collect::<Vec<i32>>()
+		};
+		assert_eq!(positions(LastRow::Left, 5), [0, 12]);
+		assert_eq!(positions(LastRow::Center, 5), [6, 18]);
+		assert_eq!(positions(LastRow::Spread, 5), [4, 21]);
+		assert_eq!(positions(LastRow::Center, 4), [12]);
+		assert_eq!(positions(LastRow::Spread, 4), [12]);
+
+		// Full rows are never moved
+		assert_eq!(positions(LastRow::Spread, 6), [0, 12, 24]);
+	}
+
+	#[test]
+	fn cells_are_split_into_pages() {
+		let layout = grid().cell_size((10, 10)).columns(2).page_size(Some(PageSize::Cells(3))).layout(7);
+		assert_eq!((layout.cells_per_page, layout.pages, layout.rows), (3, 3, 2));
+		assert_eq!(layout.output_size(), (20, 20));
+		assert_eq!((layout.page(2), layout.page(3), layout.page(6)), (0, 1, 2));
+		assert_eq!(layout.cell_rect(3), (0, 0, 10, 10));
+		assert_eq!(layout.cell_rect(4), (10, 0, 10, 10));
+		assert_eq!(layout.cell_rect(6), (0, 0, 10, 10));
+
+		let layout = grid().cell_size((10, 10)).columns(3).page_size(Some(PageSize::Rows(2))).layout(7);
+		assert_eq!((layout.cells_per_page, layout.pages, layout.rows), (6, 2, 2));
+
+		// A single page is only as large as its cells need
+		let layout = grid().cell_size((10, 10)).columns(2).page_size(Some(PageSize::Cells(10))).layout(4);
+		assert_eq!((layout.cells_per_page, layout.pages, layout.rows), (4, 1, 2));
+	}
+}
@@ -0,0 +1,17 @@
+pub mod align;
+pub mod cache;
+pub mod detector;
+pub mod error;
+pub mod exif;
+pub mod faces;
+pub mod gallery;
+pub mod geom;
+pub mod grid;
+pub mod manifest;
+pub mod model;
+pub mod sort;
+
+pub use error::{Error, Result};
+pub use grid::{
+	Cell, Detection, FaceGrid, GridLayout, GridOutput, ImageOutcome, LastRow, OutputFit, PageSize, Progress,
+};
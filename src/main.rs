@@ -1,239 +1,762 @@
-use std::path::PathBuf;
-
-use glob::{GlobError, glob};
-use image::{ImageBuffer, Pixel, RgbImage, Rgba, RgbaImage, imageops};
-use rust_faces::{
-	BlazeFaceParams, FaceDetection, FaceDetectorBuilder, InferParams, Provider, ToArray3, ToRgb8,
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use face_grid::align::{AlignMode, ResampleFilter};
+use face_grid::cache::{self, CachedFaceSource};
+use face_grid::detector::{DetectorConfig, DetectorKind, build_face_detector};
+use face_grid::faces::{
+	DetectorFaceSource, FaceSource, FaceSourceKind, FixedFaceSource, LazyFaceSource, MultiFacePolicy,
+	SidecarFaceSource,
 };
+use face_grid::gallery::gallery_html;
+use face_grid::geom::Length;
+use face_grid::manifest::{FaceManifest, GridManifest, ManifestFaceSource, ManifestImage};
+use face_grid::sort::{SortOrder, random_seed};
+use face_grid::{Detection, Error, FaceGrid, LastRow, OutputFit, PageSize, Progress, Result, model};
+use glob::glob;
+use image::{DynamicImage, ImageFormat, Rgba};
 use structopt::StructOpt;
+use strum::VariantNames;
 
-use geom::{WHf, WHi, XYWHi, XYi, fit_inside, intersect, whf_to_whi, xyf_to_xyi};
-use parsing::parse_image_dimensions;
+use parsing::{
+	parse_angle, parse_aspect_ratio, parse_color, parse_image_dimensions, parse_length,
+	parse_positive_dimensions, parse_positive_float, parse_positive_integer, parse_rect, parse_spacing,
+};
 
-pub mod geom;
 pub mod parsing;
 pub mod terminal;
 
-/**
- * Copy one image on top of another
- */
-fn copy_image(bottom: &mut RgbaImage, top: &RgbImage, cell_top_offset: XYi, cell: XYWHi) {
-	// Find paintable intersection between bottom and top
-	let bottom_rect = (0, 0, cell.2, cell.3);
-	let top_rect = (cell_top_offset.0, cell_top_offset.1, top.width(), top.height());
-	let intersection = intersect(bottom_rect, top_rect);
-	if intersection.is_none() {
-		panic!("Cannot blend image; no intersection between bottom and top image.");
-	}
-	let intersection_rect = intersection.unwrap();
-
-	let dst_x1 = intersection_rect.0 + cell.0;
-	let dst_y1 = intersection_rect.1 + cell.1;
-	let dst_x2 = intersection_rect.0 + intersection_rect.2 as i32 + cell.0;
-	let dst_y2 = intersection_rect.1 + intersection_rect.3 as i32 + cell.1;
-
-	for dst_y in dst_y1..dst_y2 {
-		let src_y = (dst_y - cell_top_offset.1 - cell.1) as u32;
-		for dst_x in dst_x1..dst_x2 {
-			let src_x = (dst_x - cell_top_offset.0 - cell.0) as u32;
-			let top_px: [u8; 3] = top
-				.get_pixel(src_x, src_y)
-				.channels()
-				.to_owned()
-				.try_into()
-				.expect("converting pixels to array");
-			bottom.put_pixel(dst_x as u32, dst_y as u32, Rgba([top_px[0], top_px[1], top_px[2], 255]));
-		}
-	}
-}
+/// Input files that were not used, with the reason.
+type SkippedFiles = Vec<(PathBuf, Error)>;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "face-grid", about = "Creates a grid of face-aligned images.")]
 struct Opt {
+	/// Number of images to process in parallel. If omitted, uses one per CPU core
+	#[structopt(long, default_value = "0", global = true)]
+	jobs: usize,
+
+	#[structopt(subcommand)]
+	command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+	/// Finds the faces in the input images, and writes them to a manifest file that can be edited by hand
+	Detect {
+		#[structopt(flatten)]
+		detection: DetectionOpt,
+
+		/// Manifest file name (e.g., "faces.json")
+		#[structopt(long, default_value = "faces.json", parse(from_os_str))]
+		output: PathBuf,
+	},
+	/// Creates the grid from the faces listed in a manifest file written by "detect"
+	Render {
+		/// Manifest file written by "detect" (e.g., "faces.json"). Image paths in it are relative to the current directory
+		#[structopt(long, default_value = "faces.json", parse(from_os_str))]
+		faces: PathBuf,
+
+		#[structopt(flatten)]
+		grid: GridOpt,
+	},
+	/// Finds the faces in the input images, and creates the grid (the default command)
+	Run {
+		#[structopt(flatten)]
+		detection: DetectionOpt,
+
+		#[structopt(flatten)]
+		grid: GridOpt,
+	},
+}
+
+// Options for finding faces in the input images
+#[derive(Debug, StructOpt)]
+struct DetectionOpt {
 	/// File mask (e.g., "images/*.jpg")
 	#[structopt(long, default_value = "*.jpg")]
 	input: String,
 
-	/// Output image dimensions (e.g., "800x600")
-	#[structopt(long, default_value = "100x100", parse(try_from_str = parse_image_dimensions))]
+	/// Path to the ONNX face detection model (or a directory containing it). If omitted, uses the cached model, downloading it if needed
+	#[structopt(long, env = model::MODEL_PATH_ENV, parse(from_os_str))]
+	model_path: Option<PathBuf>,
+
+	/// Directory where downloaded models are cached for offline reuse (defaults to "~/.rust_faces")
+	#[structopt(long, env = model::MODEL_CACHE_DIR_ENV, parse(from_os_str))]
+	model_cache_dir: Option<PathBuf>,
+
+	/// Face detector to use
+	#[structopt(long, default_value = "blazeface640", possible_values = DetectorKind::VARIANTS, case_insensitive = true)]
+	detector: DetectorKind,
+
+	/// Size images are resized to before detection, for BlazeFace (larger finds smaller faces, but is slower)
+	// rust-faces' default is 1280, but it finds no faces in most images; 80 works too
+	#[structopt(long, default_value = "160")]
+	target_size: usize,
+
+	/// Minimum detection score (e.g., "0.9"). For MTCNN, applies to its last stage. If omitted, uses the detector default. Faces below it are never found, so they aren't cached or written by "detect"; see also "--min-confidence"
+	#[structopt(long)]
+	score_threshold: Option<f32>,
+
+	/// Overlap (intersection over union) above which detections are merged
+	#[structopt(long, default_value = "0.3")]
+	nms_iou: f32,
+
+	/// Minimum face size in pixels searched for by MTCNN (larger is faster)
+	#[structopt(long, default_value = "24")]
+	mtcnn_min_face_size: usize,
+
+	/// Where to get faces from: the face detector, "<image>.faces.json" sidecar files, or a fixed rectangle
+	#[structopt(long, default_value = "detector", possible_values = FaceSourceKind::VARIANTS, case_insensitive = true)]
+	face_source: FaceSourceKind,
+
+	/// Face rectangle used by the "fixed" face source (e.g., "100,80,300,400" for x,y,width,height)
+	#[structopt(long, parse(try_from_str = parse_rect), required_if("face-source", "fixed"))]
+	face_rect: Option<(u32, u32, u32, u32)>,
+
+	/// Don't read or write the detection cache
+	#[structopt(long)]
+	no_cache: bool,
+
+	/// Directory where detected faces are cached (defaults to "~/.cache/face-grid")
+	#[structopt(long, env = cache::FACE_CACHE_DIR_ENV, parse(from_os_str), conflicts_with = "no-cache")]
+	face_cache_dir: Option<PathBuf>,
+
+	/// Run the detector again for every image, replacing the cached faces
+	#[structopt(long, conflicts_with = "no-cache")]
+	refresh_cache: bool,
+}
+
+// Options for selecting, aligning, and rendering faces into the grid
+#[derive(Debug, StructOpt)]
+struct GridOpt {
+	/// Output image dimensions (e.g., "800x600"). With "--output-size", only its aspect ratio is used
+	#[structopt(long, default_value = "100x100", parse(try_from_str = parse_positive_dimensions))]
 	cell_size: (u32, u32),
 
-	/// Scale of the face (e.g., "0.5")
-	#[structopt(long, default_value = "1")]
+	/// Exact size of the output image (e.g., "3000x2000"); the columns and cell size are chosen to best fill it. Fails if the images can't fit, e.g. with large margins
+	#[structopt(long, parse(try_from_str = parse_image_dimensions), conflicts_with_all = &["columns", "rows-per-page"])]
+	output_size: Option<(u32, u32)>,
+
+	/// Aspect ratio of the output image (e.g., "16:9" or "1.5"); the columns are chosen to best fill it
+	#[structopt(long, parse(try_from_str = parse_aspect_ratio), conflicts_with_all = &["output-size", "columns", "rows-per-page"])]
+	output_aspect: Option<f32>,
+
+	/// Space between cells, in pixels (e.g., "10"), or horizontal and vertical (e.g., "10x20")
+	#[structopt(long, default_value = "0", parse(try_from_str = parse_spacing))]
+	gutter: (u32, u32),
+
+	/// Space around the grid, in pixels (e.g., "20"), or horizontal and vertical (e.g., "20x40")
+	#[structopt(long, default_value = "0", parse(try_from_str = parse_spacing))]
+	margin: (u32, u32),
+
+	/// Color where there are no faces: hex (e.g., "#ff8800" or "#ff880080"), a name (e.g., "white"), or "transparent". If omitted, uses transparent for output formats that support it (e.g., PNG), and black otherwise (e.g., JPEG)
+	#[structopt(long, parse(try_from_str = parse_color))]
+	background: Option<Rgba<u8>>,
+
+	/// Scale of the face, when aligning by box (e.g., "0.5")
+	#[structopt(long, default_value = "1", parse(try_from_str = parse_positive_float))]
 	face_scale: f32,
 
 	/// Output file name (e.g., "output.png")
 	#[structopt(long, default_value = "face-stack-output.jpg", parse(from_os_str))]
 	output: PathBuf,
 
-	/// Number of columns to use in the image. If omitted, try as close as possible to get a square.
+	/// Also write a JSON file describing where each source image landed in the grid (e.g., "grid.json")
+	#[structopt(long, parse(from_os_str))]
+	manifest: Option<PathBuf>,
+
+	/// Also write an HTML page showing the grid, with each cell linking to its source image (e.g., "grid.html")
+	#[structopt(long, parse(from_os_str))]
+	html: Option<PathBuf>,
+
+	/// Number of columns to use in the image. If omitted, the number of columns is chosen to get close to "--grid-aspect" while leaving few empty cells
 	#[structopt(long, default_value = "0")]
 	columns: u32,
 
-	/// Number of maximum valid images to use for input
+	/// Aspect ratio of the grid that the automatic number of columns aims for (e.g., "16:9" or "1.5"). If omitted, aims for a square
+	#[structopt(long, parse(try_from_str = parse_aspect_ratio), conflicts_with_all = &["columns", "output-size", "output-aspect"])]
+	grid_aspect: Option<f32>,
+
+	/// Where to place the images of a last row that isn't full: aligned to the left, centered, or spread across the row so it's as wide as the others ("stretch" is the same as "spread"; the images keep their size)
+	#[structopt(long, default_value = "left", possible_values = LastRow::NAMES, case_insensitive = true)]
+	last_row: LastRow,
+
+	/// Order of the cells in the grid. If omitted, uses the order of the input files
+	#[structopt(long, possible_values = SortOrder::VARIANTS, case_insensitive = true)]
+	sort: Option<SortOrder>,
+
+	/// Reverse the order of the cells
+	#[structopt(long)]
+	reverse: bool,
+
+	/// Place the cells in random order; the same as "--sort random"
+	#[structopt(long, conflicts_with = "sort")]
+	shuffle: bool,
+
+	/// Seed for the random order of "--shuffle" or "--sort random", to repeat a previous one. If omitted, a new seed is used, and printed
+	#[structopt(long)]
+	seed: Option<u64>,
+
+	/// Split the grid into pages with up to this many images each, saved as numbered files (e.g., "output-001.jpg")
+	#[structopt(long, parse(try_from_str = parse_positive_integer))]
+	per_page: Option<u32>,
+
+	/// Split the grid into pages with up to this many rows each, saved as numbered files (e.g., "output-001.jpg")
+	#[structopt(long, conflicts_with = "per-page", parse(try_from_str = parse_positive_integer))]
+	rows_per_page: Option<u32>,
+
+	/// Number of maximum valid images to use for input. With "--sort" or "--reverse", all images are read, and the first ones in the sorted order are used
 	#[structopt(long, default_value = "0")]
 	max_images: u32,
+
+	/// What to do with images with more than one face: skip them, use the largest, most confident, or center-most face, or use all faces (one cell each)
+	#[structopt(long, default_value = "skip", possible_values = MultiFacePolicy::VARIANTS, case_insensitive = true)]
+	multi_face: MultiFacePolicy,
+
+	/// Minimum confidence of a face for it to be used (e.g., "0.98"). Unlike the detector's "--score-threshold", it applies to faces from any source (including cached faces and manifests), so it can be changed without detecting faces again
+	#[structopt(long, default_value = "0")]
+	min_confidence: f32,
+
+	/// Minimum size of a face for it to be used, in pixels (e.g., "64px") or as a percentage of the shorter side of the image (e.g., "10%")
+	#[structopt(long, parse(try_from_str = parse_length))]
+	min_face_size: Option<Length>,
+
+	/// Rotate faces so their eyes are level, using the eye landmarks (when the face source provides them)
+	#[structopt(long)]
+	level_eyes: bool,
+
+	/// Maximum rotation applied when leveling the eyes, in degrees. Faces tilted beyond that are rotated by this amount only
+	#[structopt(long, default_value = "45", parse(try_from_str = parse_angle))]
+	max_rotation: f32,
+
+	/// How to align faces in their cells: centering the face box, or placing the eyes at fixed positions
+	#[structopt(long, default_value = "box", possible_values = AlignMode::VARIANTS, case_insensitive = true)]
+	align: AlignMode,
+
+	/// Distance between the eyes when aligning by eyes, as a fraction of the cell width
+	#[structopt(long, default_value = "0.25", parse(try_from_str = parse_positive_float))]
+	eye_distance: f32,
+
+	/// Height of the eyes when aligning by eyes, as a fraction of the cell height from its top
+	#[structopt(long, default_value = "0.4")]
+	eye_height: f32,
+
+	/// Filter used when resampling faces; faster filters are useful for draft previews
+	#[structopt(long, default_value = "lanczos3", possible_values = ResampleFilter::VARIANTS, case_insensitive = true)]
+	filter: ResampleFilter,
+}
+
+/**
+ * Create the source of face positions requested in the options
+ */
+fn build_face_source(opt: &DetectionOpt) -> Result<Box<dyn FaceSource>> {
+	match opt.face_source {
+		FaceSourceKind::Detector => {
+			let detector_config = DetectorConfig {
+				kind: opt.detector,
+				target_size: opt.target_size,
+				score_threshold: opt.score_threshold,
+				nms_iou: opt.nms_iou,
+				mtcnn_min_face_size: opt.mtcnn_min_face_size,
+			};
+			let model_path = opt.model_path.clone();
+			let model_cache_dir = opt.model_cache_dir.clone().or_else(model::downloads_dir);
+			let cache_key = detector_config
+				.cache_key(model_path.as_deref(), model_cache_dir.as_deref())
+				.map_err(Error::Model)?;
+			let build_detector = move || -> Result<Box<dyn FaceSource>> {
+				let detector =
+					build_face_detector(&detector_config, model_path.as_deref(), model_cache_dir.as_deref())
+						.map_err(Error::Model)?;
+				Ok(Box::new(DetectorFaceSource {
+					detector,
+				}))
+			};
+
+			let face_cache_dir = opt.face_cache_dir.clone().or_else(cache::default_dir);
+			match face_cache_dir {
+				Some(face_cache_dir) if !opt.no_cache => {
+					// The model is only loaded when an image isn't in the cache
+					let face_source = Box::new(LazyFaceSource::new(build_detector));
+					Ok(Box::new(CachedFaceSource::new(
+						face_source,
+						face_cache_dir,
+						cache_key,
+						opt.refresh_cache,
+					)))
+				}
+				_ => build_detector(),
+			}
+		}
+		FaceSourceKind::Sidecar => Ok(Box::new(SidecarFaceSource)),
+		FaceSourceKind::Fixed => {
+			let (x, y, width, height) = opt
+				.face_rect
+				.ok_or(Error::InvalidOption("the fixed face source needs a --face-rect".to_string()))?;
+			Ok(Box::new(FixedFaceSource {
+				rect: (x as f32, y as f32, width as f32, height as f32),
+			}))
+		}
+	}
+}
+
+/**
+ * Create a grid with the options given, getting faces from a source
+ */
+fn build_grid(face_source: Box<dyn FaceSource>, opt: &GridOpt, jobs: usize) -> Result<FaceGrid> {
+	let supports_transparency = supports_transparency(&opt.output);
+	let background = match opt.background {
+		Some(background) if background.0[3] < 255 && !supports_transparency => {
+			return Err(Error::InvalidOption(format!(
+				"the background of {:?} can't be transparent, since its format doesn't support transparency",
+				opt.output
+			)));
+		}
+		Some(background) => background,
+		None if supports_transparency => Rgba([0, 0, 0, 0]),
+		None => Rgba([0, 0, 0, 255]),
+	};
+
+	let sort = if opt.shuffle {
+		Some(SortOrder::Random)
+	} else {
+		opt.sort
+	};
+	let seed = match opt.seed {
+		Some(_) if sort != Some(SortOrder::Random) => {
+			return Err(Error::InvalidOption(
+				"--seed only applies to a random order; add --shuffle or --sort random".to_string(),
+			));
+		}
+		Some(seed) => Some(seed),
+		None if sort == Some(SortOrder::Random) => {
+			let seed = random_seed();
+			println!("Using random seed {} (pass \"--seed {}\" to repeat the same order).", seed, seed);
+			Some(seed)
+		}
+		None => None,
+	};
+
+	let grid = FaceGrid::new(face_source)
+		.cell_size(opt.cell_size)
+		.gutter(opt.gutter)
+		.margin(opt.margin)
+		.page_size(page_size(opt))
+		.output_fit(output_fit(opt))
+		.face_scale(opt.face_scale)
+		.columns(opt.columns)
+		.aspect(opt.grid_aspect.unwrap_or(1.0))
+		.last_row(opt.last_row)
+		.max_images(opt.max_images)
+		.min_face(opt.min_confidence, opt.min_face_size)
+		.multi_face(opt.multi_face)
+		.align_mode(opt.align)
+		.eye_position(opt.eye_distance, opt.eye_height)
+		.level_eyes(opt.level_eyes, opt.max_rotation)
+		.background(background)
+		.filter(opt.filter)
+		.sort(sort, opt.reverse)
+		.jobs(jobs);
+	Ok(match seed {
+		Some(seed) => grid.seed(seed),
+		None => grid,
+	})
+}
+
+/**
+ * How the grid is split into pages, if it is
+ */
+fn page_size(opt: &GridOpt) -> Option<PageSize> {
+	match (opt.per_page, opt.rows_per_page) {
+		(Some(cells), _) => Some(PageSize::Cells(cells as usize)),
+		(None, Some(rows)) => Some(PageSize::Rows(rows)),
+		(None, None) => None,
+	}
+}
+
+/**
+ * What the output size is fit to, if anything
+ */
+fn output_fit(opt: &GridOpt) -> Option<OutputFit> {
+	match (opt.output_size, opt.output_aspect) {
+		(Some(size), _) => Some(OutputFit::Size(size)),
+		(None, Some(aspect)) => Some(OutputFit::Aspect(aspect)),
+		(None, None) => None,
+	}
+}
+
+/**
+ * Output file name of a page, numbered from 1 (e.g. "output-001.jpg")
+ */
+fn page_path(output: &Path, page: usize) -> PathBuf {
+	let mut file_name = output.file_stem().unwrap_or_default().to_os_string();
+	file_name.push(format!("-{:03}", page + 1));
+	if let Some(extension) = output.extension() {
+		file_name.push(".");
+		file_name.push(extension);
+	}
+	output.with_file_name(file_name)
+}
+
+/**
+ * Whether an output file format can store transparent pixels. Unknown formats are assumed to do so.
+ */
+fn supports_transparency(path: &Path) -> bool {
+	!matches!(ImageFormat::from_path(path), Ok(ImageFormat::Jpeg | ImageFormat::Pnm))
+}
+
+/**
+ * Find all files from the given input mask, with the entries that could not be read
+ */
+fn find_inputs(input: &str) -> Result<(Vec<PathBuf>, SkippedFiles)> {
+	let image_files = glob(input).map_err(|err| Error::InvalidInputPattern {
+		pattern: input.to_string(),
+		message: err.to_string(),
+	})?;
+
+	let mut paths = vec![];
+	let mut skipped = vec![];
+	for image_file in image_files {
+		match image_file {
+			Ok(path) => paths.push(path),
+			Err(err) => skipped.push((err.path().to_path_buf(), Error::ReadFile(err.error().to_string()))),
+		}
+	}
+	Ok((paths, skipped))
+}
+
+/**
+ * Exit the process because of a fatal error
+ */
+fn exit_with_error(err: Error) -> ! {
+	eprintln!("Error: {}", err);
+	std::process::exit(err.exit_code());
 }
 
 fn main() {
-	let opt = Opt::from_args();
-	let (cell_width, cell_height) = opt.cell_size;
-
-	println!("Will get files from {:?}, and output at {:?}.", opt.input, opt.output);
-
-	let face_detector =
-        // Alternative:
-        // FaceDetectorBuilder::new(FaceDetection::MtCnn(
-        //     MtCnnParams {
-        //         min_face_size: 1000,
-        //         ..Default::default()
-        //     }))
-        FaceDetectorBuilder::new(FaceDetection::BlazeFace640(
-            BlazeFaceParams {
-                // Default is 1280, but finds no images
-                // 80 works too
-                target_size: 160,
-                ..Default::default()
-            }))
-            .download()
-            .infer_params(InferParams {
-                provider: Provider::OrtCpu,
-                intra_threads: Some(5),
-                ..Default::default()
-            })
-            .build()
-            .expect("Failed to load the face detector");
-
-	// Decide where the face will be in the output image
-	let typical_face_size: WHf = (75f32, 100f32); // Typically 0.75 aspect ratio
-	let faces_rect_inside = fit_inside((cell_width as f32, cell_height as f32), typical_face_size);
-	let typical_face_scale = 0.6f32 * opt.face_scale;
-	let target_faces_rect: WHf =
-		(faces_rect_inside.0 * typical_face_scale, faces_rect_inside.1 * typical_face_scale);
-
-	// First, read all images and find faces, since we have to know how many cells we have in advance
-	let mut num_images_read = 0usize;
-
-	// Reads all images from the given input mask
-	let image_files = glob(&opt.input)
-		.expect(format!("Failed to read glob pattern: {}", opt.input).as_str())
-		.collect::<Vec<Result<PathBuf, GlobError>>>();
-
-	let mut results: Vec<(RgbImage, XYi)> = vec![];
-
-	for image_file in &image_files {
-		if let Ok(path) = image_file {
-			// File can be opened
+	// "run" is the default command, as it was before the command line had subcommands
+	let args = parsing::with_default_command(
+		std::env::args_os().collect(),
+		&["detect", "render", "run", "help"],
+		&["--jobs"],
+		"run",
+	);
+	let opt = Opt::from_iter_safe(args).unwrap_or_else(|err| {
+		if !err.use_stderr() {
+			// Help and version
+			err.exit();
+		}
+		// Invalid arguments exit like the options rejected after parsing
+		eprintln!("{}", err.message);
+		std::process::exit(Error::InvalidOption(err.message).exit_code());
+	});
+
+	let result = match &opt.command {
+		Command::Detect {
+			detection,
+			output,
+		} => detect(detection, output, opt.jobs),
+		Command::Render {
+			faces,
+			grid,
+		} => render(faces, grid, opt.jobs),
+		Command::Run {
+			detection,
+			grid,
+		} => run(detection, grid, opt.jobs),
+	};
+	if let Err(err) = result {
+		exit_with_error(err);
+	}
+}
+
+/**
+ * Find the faces in all input images, and write them to a manifest
+ */
+fn detect(opt: &DetectionOpt, output: &Path, jobs: usize) -> Result<()> {
+	println!("Will get files from {:?}, and write faces found at {:?}.", opt.input, output);
+
+	let (paths, mut skipped) = find_inputs(&opt.input)?;
+	let face_source = build_face_source(opt)?;
+
+	// All faces are kept, so they can be selected when rendering
+	let grid = FaceGrid::new(face_source).multi_face(MultiFacePolicy::All).jobs(jobs);
+
+	let mut manifest = FaceManifest::default();
+	let num_paths = paths.len();
+	grid.detect_all(&paths, |num_images_read, path, detection| {
+		terminal::erase_line_to_end();
+		print!(
+			"(Step 1/1) ({}/{}) Reading {:?}",
+			num_images_read + 1,
+			num_paths,
+			path.file_name().unwrap_or(path.as_os_str())
+		);
+
+		match detection {
+			Ok(detection) => {
+				println!("{}", describe_detection(&detection, None));
+				manifest.images.push(ManifestImage {
+					path: detection.path,
+					faces: detection.faces,
+				});
+				terminal::cursor_up();
+			}
+			Err(err) => {
+				println!("; {}, skipping.", err);
+				skipped.push((path.to_path_buf(), err));
+			}
+		}
+		true
+	})?;
+
+	terminal::erase_line_to_end();
+	let num_faces = manifest.images.iter().map(|image| image.faces.len()).sum::<usize>();
+	println!("(Step 1/1) Done. {} images processed, with {} faces found.", num_paths, num_faces);
+
+	manifest.write(output)?;
+	print_skipped(&skipped);
+	Ok(())
+}
+
+/**
+ * Create the grid from the faces listed in a manifest
+ */
+fn render(faces: &Path, opt: &GridOpt, jobs: usize) -> Result<()> {
+	println!("Will get faces from {:?}, and output at {:?}.", faces, opt.output);
+
+	let manifest = FaceManifest::read(faces)?;
+	let grid = build_grid(Box::new(ManifestFaceSource::new(&manifest)), opt, jobs)?;
+	build_and_save(grid, opt, &manifest.paths(), vec![])
+}
+
+/**
+ * Find the faces in all input images, and create the grid
+ */
+fn run(detection: &DetectionOpt, opt: &GridOpt, jobs: usize) -> Result<()> {
+	println!("Will get files from {:?}, and output at {:?}.", detection.input, opt.output);
+
+	let (paths, skipped) = find_inputs(&detection.input)?;
+	let grid = build_grid(build_face_source(detection)?, opt, jobs)?;
+	build_and_save(grid, opt, &paths, skipped)
+}
+
+/**
+ * Read all images, blend their faces into the grid, and save it
+ */
+fn build_and_save(grid: FaceGrid, opt: &GridOpt, paths: &[PathBuf], skipped: SkippedFiles) -> Result<()> {
+	let num_steps = if opt.output_size.is_some() || opt.output_aspect.is_some() {
+		3
+	} else {
+		2
+	};
+	let step = |step: usize| format!("(Step {}/{})", step + num_steps - 2, num_steps);
+
+	let mut skipped =
+		skipped.into_iter().map(|(path, err)| (path, err.to_string())).collect::<Vec<(PathBuf, String)>>();
+	let mut pages_info = String::new();
+	let result = grid.inputs(paths.to_vec()).run_with_progress(|progress| match progress {
+		Progress::Detected {
+			index,
+			num_inputs,
+			path,
+			detection,
+		} => {
 			terminal::erase_line_to_end();
 			print!(
-				"(Step 1/2) ({}/{}) Reading {:?}",
-				num_images_read + 1,
-				image_files.len(),
-				&path.file_name().unwrap()
+				"(Step 1/3) ({}/{}) Reading {:?}",
+				index + 1,
+				num_inputs,
+				path.file_name().unwrap_or(path.as_os_str())
 			);
-
-			if let Ok(img) = image::open(&path) {
-				// Is a valid image file
-				print!(", {:?}x{:?}", img.width(), img.height());
-				let array3_image = img.into_rgb8().into_array3();
-				let faces = face_detector.detect(array3_image.view().into_dyn()).unwrap();
-				print!(", {} faces", faces.len());
-
-				if faces.len() == 1 {
-					// Has a valid face
-					println!(", confidence {:?}", faces[0].confidence);
-
-					let rgb_image = array3_image.to_rgb8();
-					let face_rect = &faces[0].rect;
-
-					// Find out what the face size should be inside our face target box
-					let target_face_rect: WHf =
-						fit_inside(target_faces_rect, (face_rect.width, face_rect.height));
-					let new_image_scale = target_face_rect.0 / face_rect.width;
-					let new_image_size: WHi = whf_to_whi((
-						rgb_image.width() as f32 * new_image_scale,
-						rgb_image.height() as f32 * new_image_scale,
-					));
-
-					// Scale the image appropriately
-					let resized_image =
-						imageops::resize(&rgb_image, new_image_size.0, new_image_size.1, imageops::Lanczos3);
-
-					// Get all the options
-					let param_offset: XYi = xyf_to_xyi((
-						cell_width as f32 / 2.0 - (face_rect.x + face_rect.width / 2.0) * new_image_scale,
-						cell_height as f32 / 2.0 - (face_rect.y + face_rect.height / 2.0) * new_image_scale,
-					));
-
-					results.push((resized_image, param_offset));
-
+			match detection {
+				Ok(detection) => {
+					println!("{}", describe_detection(detection, Some(opt.multi_face)));
 					terminal::cursor_up();
-				} else {
-					println!("; no valid faces, skipping.");
 				}
-			} else {
-				println!("; invalid image, skipping.");
+				Err(err) => {
+					println!("; {}, skipping.", err);
+					skipped.push((path.to_path_buf(), err.to_string()));
+				}
 			}
 		}
+		Progress::DetectionDone {
+			num_inputs,
+			num_cells,
+		} => {
+			terminal::erase_line_to_end();
+			println!("(Step 1/3) Done. {} images processed, with {} faces found.", num_inputs, num_cells);
+		}
+		Progress::Aligned {
+			index,
+			num_images,
+			outcome,
+			from_detection,
+		} => {
+			terminal::erase_line_to_end();
+			let action = if from_detection {
+				"Aligning faces in"
+			} else {
+				"Reading"
+			};
+			print!(
+				"{} ({}/{}) {} {:?}",
+				step(1),
+				index + 1,
+				num_images,
+				action,
+				outcome.path.file_name().unwrap_or(outcome.path.as_os_str())
+			);
+			if let Some(detection) = outcome.detection.as_ref().filter(|_| !from_detection) {
+				print!("{}", describe_detection(detection, Some(opt.multi_face)));
+			}
 
-		num_images_read += 1;
-
-		if opt.max_images > 0 && results.len() >= opt.max_images as usize {
+			match &outcome.cells {
+				Ok(_) => {
+					println!();
+					terminal::cursor_up();
+				}
+				Err(err) => {
+					println!("; {}, skipping.", err);
+					skipped.push((outcome.path.clone(), err.to_string()));
+				}
+			}
+		}
+		Progress::MaxImagesReached => {
 			terminal::erase_line_to_end();
 			println!("Reached the maximum number of input images; skipping additional files.");
-			break;
 		}
+		Progress::AlignmentDone {
+			num_images,
+			num_cells,
+		} => {
+			terminal::erase_line_to_end();
+			println!(
+				"{} Done. {} images processed, with {} valid results found (multi-face policy: {}).",
+				step(1),
+				num_images,
+				num_cells,
+				opt.multi_face
+			);
+		}
+		Progress::Arranged {
+			layout,
+		} => {
+			// Pages are only mentioned when there are several of them
+			if layout.pages > 1 {
+				pages_info = format!(", in {} pages", layout.pages);
+			}
+			let (output_width, output_height) = layout.output_size();
+			println!(
+				"The output size will be {}x{}, with {} rows and {} columns of images{}.",
+				output_width, output_height, layout.rows, layout.columns, pages_info
+			);
+		}
+		Progress::Rendered {
+			index,
+			layout,
+		} => {
+			terminal::erase_line_to_end();
+			if layout.pages > 1 {
+				println!(
+					"{} (Page {}/{}) ({}/{}) Blending image",
+					step(2),
+					layout.page(index) + 1,
+					layout.pages,
+					index + 1,
+					layout.num_cells
+				);
+			} else {
+				println!("{} ({}/{}) Blending image", step(2), index + 1, layout.num_cells);
+			}
+			terminal::cursor_up();
+		}
+	});
+	let output = match result {
+		Ok(output) => output,
+		Err(err) => {
+			print_skipped(&skipped);
+			return Err(err);
+		}
+	};
+
+	// With pages, each one is saved to a numbered file
+	let outputs = match page_size(opt) {
+		Some(_) => {
+			(0..output.layout.pages).map(|page| page_path(&opt.output, page)).collect::<Vec<PathBuf>>()
+		}
+		None => vec![opt.output.clone()],
+	};
+	for (image, path) in output.images.into_iter().zip(&outputs) {
+		// Formats without transparency get an opaque image, as the background is opaque
+		let image = if supports_transparency(path) {
+			DynamicImage::ImageRgba8(image)
+		} else {
+			DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).into_rgb8())
+		};
+		image.save(path).map_err(|err| Error::SaveOutput {
+			path: path.clone(),
+			message: err.to_string(),
+		})?;
 	}
 
 	terminal::erase_line_to_end();
-	println!(
-		"(Step 1/2) Done. {} images processed, with {} valid results found.",
-		image_files.len(),
-		results.len()
-	);
+	println!("{} Done. {} images blended{}.", step(2), output.cells.len(), pages_info);
 
-	let num_cols = if opt.columns == 0 {
-		(results.len() as f32).sqrt().ceil() as u32
-	} else {
-		opt.columns
-	};
-	let num_rows = (results.len() as f32 / num_cols as f32).ceil() as u32;
-	let output_width = num_cols * cell_width;
-	let output_height = num_rows * cell_height;
+	if let Some(manifest_path) = &opt.manifest {
+		GridManifest::new(&outputs, &output.layout, &output.cells).write(manifest_path)?;
+	}
 
-	println!(
-		"The output size will be {}x{}, with {} rows and {} columns of images.",
-		output_width, output_height, num_rows, num_cols
-	);
+	if let Some(html_path) = &opt.html {
+		let html = gallery_html(html_path, &outputs, &output.layout, &output.cells);
+		fs::write(html_path, html).map_err(|err| Error::SaveOutput {
+			path: html_path.clone(),
+			message: err.to_string(),
+		})?;
+	}
 
-	// Second, blend the valid images found
+	print_skipped(&skipped);
+	Ok(())
+}
 
-	// Create the output image
-	let mut output_image: RgbaImage =
-		ImageBuffer::from_pixel(output_width, output_height, Rgba([0, 0, 0, 0]));
+/**
+ * Describe what was found in an image, to continue its progress line. The multi-face policy is mentioned if it was used
+ */
+fn describe_detection(detection: &Detection, multi_face: Option<MultiFacePolicy>) -> String {
+	let mut description = format!(", {:?}x{:?}", detection.original_size.0, detection.original_size.1);
+	if let Some(orientation) = detection.orientation {
+		description += &format!(", reoriented (EXIF orientation {})", orientation);
+	}
 
-	let mut num_images_blended = 0;
-	for result in &results {
-		terminal::erase_line_to_end();
-		println!("(Step 2/2) ({}/{}) Blending image", num_images_blended + 1, results.len());
-		let col = num_images_blended % num_cols;
-		let row = num_images_blended / num_cols;
-		let cell_tr = (col * cell_width, row * cell_height);
-
-		copy_image(
-			&mut output_image,
-			&result.0,
-			result.1,
-			(cell_tr.0 as i32, cell_tr.1 as i32, cell_width, cell_height),
-		);
-		num_images_blended += 1;
+	description += &format!(", {} faces", detection.num_faces_accepted);
+	if detection.num_faces_accepted < detection.num_faces_found {
+		description += &format!(" ({} rejected)", detection.num_faces_found - detection.num_faces_accepted);
+	}
+	if let Some(multi_face) = multi_face.filter(|_| detection.num_faces_accepted > 1) {
+		description += &format!(" ({} policy)", multi_face);
+	}
 
-		terminal::cursor_up();
+	let confidences = detection.faces.iter().map(|face| face.confidence).collect::<Vec<f32>>();
+	match confidences.len() {
+		0 => (),
+		1 => description += &format!(", confidence {:?}", confidences[0]),
+		_ => description += &format!(", confidences {:?}", confidences),
 	}
+	description
+}
 
-	terminal::erase_line_to_end();
-	println!("(Step 2/2) Done. {} images blended.", results.len());
+/**
+ * List all files that were skipped, with the reason
+ */
+fn print_skipped<E: Display>(skipped: &[(PathBuf, E)]) {
+	if skipped.is_empty() {
+		return;
+	}
 
-	// Finally, saved the final image
-	output_image.save(&opt.output).expect("Failed to save output image");
+	println!("Skipped {} files:", skipped.len());
+	for (path, err) in skipped {
+		println!("  {:?}: {}", path, err);
+	}
 }
@@ -0,0 +1,227 @@
+// Manifest files, in JSON:
+// * Faces found in a set of images, as written by the `detect` command and read by the `render` command. It can
+//   be edited by hand in between, e.g. to fix bad detections.
+// * Where each face landed in a rendered grid, to build galleries or audit results.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::faces::{Face, FaceSource};
+use crate::geom::{WHi, XYWHf, XYWHi, XYf};
+use crate::grid::{Cell, GridLayout};
+
+/// An image and all the faces found in it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestImage {
+	/// Path of the image; relative paths are relative to the current directory.
+	pub path: PathBuf,
+	pub faces: Vec<Face>,
+}
+
+/// List of images with their faces, in the order they're placed in the grid.
+///
+/// The file is an object with an "images" list. Each image is an object with a "path" and a list of "faces",
+/// in the same format used by "<image file name>.faces.json" sidecar files. Each path can only be listed once.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FaceManifest {
+	pub images: Vec<ManifestImage>,
+}
+
+impl FaceManifest {
+	pub fn read(path: &Path) -> Result<Self> {
+		let read_error = |message: String| Error::ReadManifest {
+			path: path.to_path_buf(),
+			message,
+		};
+		let contents = fs::read_to_string(path).map_err(|err| read_error(err.to_string()))?;
+		let manifest: Self = serde_json::from_str(&contents).map_err(|err| read_error(err.to_string()))?;
+		match manifest.duplicate_path() {
+			Some(duplicate) => Err(read_error(format!(
+				"{:?} is listed more than once; list all of its faces in a single entry",
+				duplicate
+			))),
+			None => Ok(manifest),
+		}
+	}
+
+	pub fn write(&self, path: &Path) -> Result<()> {
+		write_json(path, self)
+	}
+
+	/// The first path listed more than once, if any.
+	fn duplicate_path(&self) -> Option<&Path> {
+		let mut paths = HashSet::new();
+		self.images.iter().map(|image| image.path.as_path()).find(|path| !paths.insert(*path))
+	}
+
+	/// Paths of all images, in order.
+	pub fn paths(&self) -> Vec<PathBuf> {
+		self.images.iter().map(|image| image.path.clone()).collect()
+	}
+}
+
+/// Uses the faces listed in a manifest, instead of finding them again. The manifest can't list a path more than
+/// once, as checked by `FaceManifest::read`.
+pub struct ManifestFaceSource {
+	faces: HashMap<PathBuf, Vec<Face>>,
+}
+
+impl ManifestFaceSource {
+	pub fn new(manifest: &FaceManifest) -> Self {
+		Self {
+			faces: manifest.images.iter().map(|image| (image.path.clone(), image.faces.clone())).collect(),
+		}
+	}
+}
+
+impl FaceSource for ManifestFaceSource {
+	fn find_faces(&self, path: &Path, _image: &RgbImage) -> Result<Vec<Face>> {
+		self.faces
+			.get(path)
+			.cloned()
+			.ok_or_else(|| Error::FaceDetection(format!("{:?} is not in the manifest", path)))
+	}
+}
+
+/// Where each cell of a grid came from.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GridManifest {
+	/// Output image of each page.
+	pub images: Vec<PathBuf>,
+	pub size: WHi,
+	pub columns: u32,
+	pub rows: u32,
+	pub cell_size: WHi,
+	pub gutter: WHi,
+	pub margin: WHi,
+	pub cells: Vec<GridManifestCell>,
+}
+
+/// Where a cell of a grid came from, and how its source image was placed in it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GridManifestCell {
+	/// Path of the source image.
+	pub path: PathBuf,
+	pub page: usize,
+	pub column: u32,
+	pub row: u32,
+	/// Rectangle of the cell in the output image of its page.
+	pub rect: XYWHi,
+	/// Rectangle of the face in the source image.
+	pub face_rect: XYWHf,
+	pub scale: f32,
+	/// Offset of the source image inside the cell, before rotating.
+	pub offset: XYf,
+	/// Rotation, in degrees.
+	pub rotation: f32,
+	pub confidence: f32,
+}
+
+impl GridManifest {
+	/// Describes the cells of a grid, with the output image of each page.
+	pub fn new(outputs: &[PathBuf], layout: &GridLayout, cells: &[Cell]) -> Self {
+		let cells = cells
+			.iter()
+			.enumerate()
+			.map(|(index, cell)| {
+				let (column, row) = layout.cell_position(index);
+				GridManifestCell {
+					path: cell.path.clone(),
+					page: layout.page(index),
+					column,
+					row,
+					rect: layout.cell_rect(index),
+					face_rect: cell.face.rect,
+					scale: cell.transform.scale,
+					offset: cell.transform.offset(),
+					rotation: cell.transform.angle.to_degrees(),
+					confidence: cell.face.confidence,
+				}
+			})
+			.collect();
+		Self {
+			images: outputs.to_vec(),
+			size: layout.output_size(),
+			columns: layout.columns,
+			rows: layout.rows,
+			cell_size: layout.cell_size,
+			gutter: layout.gutter,
+			margin: layout.margin,
+			cells,
+		}
+	}
+
+	pub fn write(&self, path: &Path) -> Result<()> {
+		write_json(path, self)
+	}
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+	let save_error = |message: String| Error::SaveOutput {
+		path: path.to_path_buf(),
+		message,
+	};
+	let contents = serde_json::to_string_pretty(value).map_err(|err| save_error(err.to_string()))?;
+	fs::write(path, contents + "\n").map_err(|err| save_error(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn image(path: &str, x: f32) -> ManifestImage {
+		ManifestImage {
+			path: PathBuf::from(path),
+			faces: vec![Face {
+				rect: (x, 0.0, 10.0, 10.0),
+				landmarks: vec![(1.0, 2.0), (3.0, 4.0)],
+				confidence: 0.5,
+			}],
+		}
+	}
+
+	fn manifest_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("face-grid-manifest-{}-{}.json", name, std::process::id()))
+	}
+
+	#[test]
+	fn manifests_are_read_as_written() {
+		let manifest = FaceManifest {
+			images: vec![image("b.jpg", 1.0), image("a.jpg", 2.0)],
+		};
+		let path = manifest_path("round-trip");
+		manifest.write(&path).unwrap();
+		assert_eq!(FaceManifest::read(&path).unwrap(), manifest);
+		assert_eq!(manifest.paths(), vec![PathBuf::from("b.jpg"), PathBuf::from("a.jpg")]);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn manifests_with_duplicate_paths_are_rejected() {
+		let path = manifest_path("duplicates");
+		let manifest = FaceManifest {
+			images: vec![image("a.jpg", 1.0), image("b.jpg", 2.0), image("a.jpg", 3.0)],
+		};
+		manifest.write(&path).unwrap();
+		let err = FaceManifest::read(&path).unwrap_err();
+		assert!(matches!(err, Error::ReadManifest { .. }));
+		assert!(err.to_string().contains("\"a.jpg\" is listed more than once"), "{err}");
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn manifest_face_source_finds_faces_by_path() {
+		let manifest = FaceManifest {
+			images: vec![image("a.jpg", 1.0), image("b.jpg", 2.0)],
+		};
+		let source = ManifestFaceSource::new(&manifest);
+		let image = RgbImage::new(1, 1);
+		assert_eq!(source.find_faces(Path::new("b.jpg"), &image).unwrap(), manifest.images[1].faces);
+		assert!(matches!(source.find_faces(Path::new("c.jpg"), &image), Err(Error::FaceDetection(_))));
+	}
+}
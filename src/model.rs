@@ -0,0 +1,170 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Environment variable used as a fallback when no model path is given in the command line.
+pub const MODEL_PATH_ENV: &str = "FACE_GRID_MODEL_PATH";
+
+/// Environment variable used as a fallback when no model cache directory is given in the command line.
+pub const MODEL_CACHE_DIR_ENV: &str = "FACE_GRID_MODEL_CACHE_DIR";
+
+/// File names of the BlazeFace 640 model, as used by the rust-faces model repository.
+pub const BLAZEFACE_640_FILES: &[&str] = &["blazeface-640.onnx"];
+
+/// File names of the BlazeFace 320 model.
+pub const BLAZEFACE_320_FILES: &[&str] = &["blazeface-320.onnx"];
+
+/// File names of the MTCNN models (proposal, refine, and output networks, in that order).
+pub const MTCNN_FILES: &[&str] = &["mtcnn-pnet.onnx", "mtcnn-rnet.onnx", "mtcnn-onet.onnx"];
+
+/**
+ * Directory where rust-faces stores the models it downloads
+ */
+pub fn downloads_dir() -> Option<PathBuf> {
+	std::env::home_dir().map(|home| home.join(".rust_faces"))
+}
+
+/**
+ * Find the local model files to use, either from a user-provided path or from the cache directory.
+ * The path can point to the model file itself, or to a directory containing the model files.
+ * Returns None when there's no path and the cache doesn't have the model, meaning it needs downloading.
+ */
+pub fn find_model_files(
+	model_path: Option<&Path>,
+	cache_dir: Option<&Path>,
+	file_names: &[&str],
+) -> Result<Option<Vec<PathBuf>>, String> {
+	if let Some(path) = model_path {
+		let files = if path.is_dir() {
+			file_names.iter().map(|name| path.join(name)).collect::<Vec<PathBuf>>()
+		} else if file_names.len() == 1 {
+			vec![path.to_path_buf()]
+		} else {
+			return Err(format!(
+				"The detector needs {} model files ({}); the model path {:?} should be a directory containing them.",
+				file_names.len(),
+				file_names.join(", "),
+				path
+			));
+		};
+		for file in &files {
+			validate_model_file(file)?;
+		}
+		return Ok(Some(files));
+	}
+
+	if let Some(dir) = cache_dir {
+		let files = file_names.iter().map(|name| dir.join(name)).collect::<Vec<PathBuf>>();
+		if files.iter().all(|file| file.is_file()) {
+			for file in &files {
+				validate_model_file(file)?;
+			}
+			return Ok(Some(files));
+		}
+	}
+
+	Ok(None)
+}
+
+/**
+ * Copy freshly downloaded model files to the cache directory, so they can be reused offline
+ */
+pub fn store_in_cache(cache_dir: &Path, file_names: &[&str]) -> Result<(), String> {
+	let downloads = downloads_dir().ok_or("Could not find the model download directory")?;
+	if downloads == cache_dir {
+		return Ok(());
+	}
+
+	fs::create_dir_all(cache_dir)
+		.map_err(|err| format!("Could not create model cache directory {:?}: {}", cache_dir, err))?;
+	for name in file_names {
+		fs::copy(downloads.join(name), cache_dir.join(name))
+			.map_err(|err| format!("Could not copy model file {} to {:?}: {}", name, cache_dir, err))?;
+	}
+	Ok(())
+}
+
+/**
+ * Check whether a file exists and looks like an ONNX model, to report missing or unrelated files early.
+ * ONNX files are protobuf messages that start with the IR version field (field 1, varint). This doesn't catch
+ * truncated or mismatched models, which build_face_detector() checks by loading and running them.
+ */
+fn validate_model_file(path: &Path) -> Result<(), String> {
+	let mut first_byte = [0u8; 1];
+	File::open(path)
+		.and_then(|mut file| file.read(&mut first_byte))
+		.map_err(|err| format!("Could not read model file {:?}: {}", path, err))
+		.and_then(|num_read| {
+			if num_read == 1 && first_byte[0] == 0x08 {
+				Ok(())
+			} else {
+				Err(format!("Model file {:?} is not a valid ONNX model.", path))
+			}
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A file that passes the ONNX header check.
+	const ONNX_HEADER: &[u8] = &[0x08, 0x07];
+
+	fn test_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("face-grid-model-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	#[test]
+	fn missing_and_non_onnx_files_are_rejected() {
+		let dir = test_dir("invalid");
+		let missing = dir.join("missing.onnx");
+		let text = dir.join("model.txt");
+		fs::write(&text, "not a model").unwrap();
+
+		assert!(
+			find_model_files(Some(&missing), None, BLAZEFACE_640_FILES)
+				.unwrap_err()
+				.contains("Could not read")
+		);
+		assert!(
+			find_model_files(Some(&text), None, BLAZEFACE_640_FILES)
+				.unwrap_err()
+				.contains("not a valid ONNX")
+		);
+		// A directory missing the model file
+		assert!(find_model_files(Some(&dir), None, BLAZEFACE_640_FILES).is_err());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn mtcnn_models_are_found_in_a_directory() {
+		let dir = test_dir("mtcnn");
+		for name in MTCNN_FILES {
+			fs::write(dir.join(name), ONNX_HEADER).unwrap();
+		}
+
+		let files = find_model_files(Some(&dir), None, MTCNN_FILES).unwrap().unwrap();
+		assert_eq!(files, MTCNN_FILES.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+		let err = find_model_files(Some(&dir.join(MTCNN_FILES[0])), None, MTCNN_FILES).unwrap_err();
+		assert!(err.contains("should be a directory"));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn cached_models_are_used_when_there_is_no_model_path() {
+		let dir = test_dir("cache");
+		assert_eq!(find_model_files(None, Some(&dir), BLAZEFACE_320_FILES), Ok(None));
+
+		fs::write(dir.join(BLAZEFACE_320_FILES[0]), ONNX_HEADER).unwrap();
+		let files = find_model_files(None, Some(&dir), BLAZEFACE_320_FILES).unwrap();
+		assert_eq!(files, Some(vec![dir.join(BLAZEFACE_320_FILES[0])]));
+		// A given model path takes precedence over the cache
+		let other = dir.join("other.onnx");
+		fs::write(&other, ONNX_HEADER).unwrap();
+		assert_eq!(find_model_files(Some(&other), Some(&dir), BLAZEFACE_320_FILES), Ok(Some(vec![other])));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
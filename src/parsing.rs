@@ -1,5 +1,10 @@
 // Originally (partly) from https://github.com/zeh/random-art-generator/blob/main/src/generator/utils/parsing.rs
 
+use std::ffi::OsString;
+
+use face_grid::geom::Length;
+use image::Rgba;
+
 fn parse_integer(src: &str) -> Result<u32, &str> {
 	src.parse::<u32>().or(Err("Could not parse integer value"))
 }
@@ -8,11 +13,305 @@ fn parse_integer_list(src: &str, divider: char) -> Result<Vec<u32>, &str> {
 	src.split(divider).collect::<Vec<&str>>().iter().map(|&e| parse_integer(e)).collect()
 }
 
+/// Parses a positive integer (e.g., a number of cells), rejecting zero.
+pub fn parse_positive_integer(src: &str) -> Result<u32, &str> {
+	match parse_integer(src)? {
+		0 => Err("The value should be at least 1"),
+		value => Ok(value),
+	}
+}
+
 /// Parses a dimensions string (999x999) into a (u32, u32) width/height tuple.
 pub fn parse_image_dimensions(src: &str) -> Result<(u32, u32), &str> {
-	let values = parse_integer_list(&src, 'x')?;
+	let values = parse_integer_list(src, 'x')?;
 	match values.len() {
 		2 => Ok((values[0], values[1])),
 		_ => Err("Dimensions should use WIDTHxHEIGHT"),
 	}
 }
+
+/// Parses a dimensions string (999x999) like `parse_image_dimensions()`, rejecting zero sizes.
+pub fn parse_positive_dimensions(src: &str) -> Result<(u32, u32), &str> {
+	match parse_image_dimensions(src)? {
+		(0, _) | (_, 0) => Err("The width and height should be at least 1"),
+		dimensions => Ok(dimensions),
+	}
+}
+
+/// Parses a positive number (e.g., a scale), rejecting zero, negative, and non-finite values.
+pub fn parse_positive_float(src: &str) -> Result<f32, &str> {
+	match src.parse::<f32>() {
+		Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+		_ => Err("The value should be a number greater than 0"),
+	}
+}
+
+/// Parses a spacing string, either the same for both axes ("10") or horizontal and vertical (e.g. "10x20"), into a (u32, u32) tuple.
+pub fn parse_spacing(src: &str) -> Result<(u32, u32), &str> {
+	let values = parse_integer_list(src, 'x')?;
+	match values.len() {
+		1 => Ok((values[0], values[0])),
+		2 => Ok((values[0], values[1])),
+		_ => Err("Spacing should use PIXELS or HORIZONTALxVERTICAL"),
+	}
+}
+
+/// Parses a rectangle string (X,Y,WIDTH,HEIGHT, e.g. "10,20,300,400") into a (u32, u32, u32, u32) x/y/width/height tuple.
+pub fn parse_rect(src: &str) -> Result<(u32, u32, u32, u32), &str> {
+	let values = parse_integer_list(src, ',')?;
+	match values.len() {
+		4 => Ok((values[0], values[1], values[2], values[3])),
+		_ => Err("Rectangles should use X,Y,WIDTH,HEIGHT"),
+	}
+}
+
+/// Parses an aspect ratio string, either as width and height ("16:9") or as a number ("1.5"), into a width / height f32.
+pub fn parse_aspect_ratio(src: &str) -> Result<f32, &str> {
+	let error = "Aspect ratios should use WIDTH:HEIGHT (e.g., \"16:9\") or a number (e.g., \"1.5\")";
+	let aspect_ratio = match src.split_once(':') {
+		Some((width, height)) => {
+			width.parse::<f32>().or(Err(error))? / height.parse::<f32>().or(Err(error))?
+		}
+		None => src.parse::<f32>().or(Err(error))?,
+	};
+	if aspect_ratio.is_finite() && aspect_ratio > 0.0 {
+		Ok(aspect_ratio)
+	} else {
+		Err(error)
+	}
+}
+
+/// Parses a length string into pixels ("48px") or a percentage ("10%"). The unit is required, so values can't be misread.
+pub fn parse_length(src: &str) -> Result<Length, &str> {
+	let error = "Lengths should be pixels (e.g., \"48px\") or percentages (e.g., \"10%\")";
+	let length = if let Some(percentage) = src.strip_suffix('%') {
+		Length::Fraction(percentage.parse::<f32>().or(Err(error))? / 100.0)
+	} else if let Some(pixels) = src.strip_suffix("px") {
+		Length::Pixels(pixels.parse::<f32>().or(Err(error))?)
+	} else {
+		return Err(error);
+	};
+	match length {
+		Length::Pixels(value) | Length::Fraction(value) if value.is_finite() && value >= 0.0 => Ok(length),
+		_ => Err(error),
+	}
+}
+
+/// Parses an angle in degrees (e.g., "30"), rejecting negative and non-finite values.
+pub fn parse_angle(src: &str) -> Result<f32, &str> {
+	let error = "Angles should be a number of degrees, at least 0 (e.g., \"30\")";
+	match src.parse::<f32>() {
+		Ok(angle) if angle.is_finite() && angle >= 0.0 => Ok(angle),
+		_ => Err(error),
+	}
+}
+
+/// Parses a color string into a RGBA color: hex ("#f80", "#ff8800", or with alpha, "#ff880080"), a name ("white"), or "transparent".
+pub fn parse_color(src: &str) -> Result<Rgba<u8>, &str> {
+	let error = "Colors should be hex (e.g., \"#ff8800\"), a name (e.g., \"white\"), or \"transparent\"";
+	let named = match src.to_lowercase().as_str() {
+		"transparent" => Some([0, 0, 0, 0]),
+		"black" => Some([0, 0, 0, 255]),
+		"white" => Some([255, 255, 255, 255]),
+		"gray" | "grey" => Some([128, 128, 128, 255]),
+		"red" => Some([255, 0, 0, 255]),
+		"green" => Some([0, 128, 0, 255]),
+		"blue" => Some([0, 0, 255, 255]),
+		"yellow" => Some([255, 255, 0, 255]),
+		"cyan" => Some([0, 255, 255, 255]),
+		"magenta" => Some([255, 0, 255, 255]),
+		_ => None,
+	};
+	if let Some(color) = named {
+		return Ok(Rgba(color));
+	}
+
+	let hex = src.strip_prefix('#').unwrap_or(src);
+	if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+		return Err(error);
+	}
+	let digits = match hex.len() {
+		// Short form: each digit is repeated
+		3 | 4 => hex.chars().map(|c| c.to_string().repeat(2)).collect::<Vec<String>>(),
+		6 | 8 => (0..hex.len()).step_by(2).map(|i| hex[i..i + 2].to_string()).collect::<Vec<String>>(),
+		_ => return Err(error),
+	};
+	let mut color = [0, 0, 0, 255];
+	for (channel, digit) in color.iter_mut().zip(digits) {
+		*channel = u8::from_str_radix(&digit, 16).or(Err(error))?;
+	}
+	Ok(Rgba(color))
+}
+
+/// Inserts the default subcommand into the arguments when none is given, so "face-grid --input ..." works as
+/// "face-grid run --input ...". Global options before it (each taking a value) are skipped; help and version
+/// flags are left alone.
+pub fn with_default_command(
+	mut args: Vec<OsString>,
+	commands: &[&str],
+	global_options: &[&str],
+	default: &str,
+) -> Vec<OsString> {
+	let mut index = 1;
+	while let Some(arg) = args.get(index).and_then(|arg| arg.to_str()) {
+		if global_options.contains(&arg) {
+			index += 2;
+		} else if global_options.iter().any(|option| arg.starts_with(&format!("{}=", option))) {
+			index += 1;
+		} else {
+			break;
+		}
+	}
+
+	let index = index.min(args.len());
+	let has_command = args
+		.get(index)
+		.and_then(|arg| arg.to_str())
+		.is_some_and(|arg| commands.contains(&arg) || ["-h", "--help", "-V", "--version"].contains(&arg));
+	if !has_command {
+		args.insert(index, default.into());
+	}
+	args
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_image_dimensions() {
+		assert_eq!(parse_image_dimensions("800x600"), Ok((800, 600)));
+		assert!(parse_image_dimensions("800").is_err());
+		assert!(parse_image_dimensions("800x600x2").is_err());
+		assert!(parse_image_dimensions("800xa").is_err());
+	}
+
+	#[test]
+	fn parses_positive_dimensions() {
+		assert_eq!(parse_positive_dimensions("800x600"), Ok((800, 600)));
+		assert!(parse_positive_dimensions("0x600").is_err());
+		assert!(parse_positive_dimensions("800x0").is_err());
+		assert!(parse_positive_dimensions("800").is_err());
+	}
+
+	#[test]
+	fn parses_positive_floats() {
+		assert_eq!(parse_positive_float("0.5"), Ok(0.5));
+		assert_eq!(parse_positive_float("2"), Ok(2.0));
+		for value in ["0", "-0.5", "NaN", "inf", "a", ""] {
+			assert!(parse_positive_float(value).is_err(), "{value:?}");
+		}
+	}
+
+	#[test]
+	fn parses_positive_integers() {
+		assert_eq!(parse_positive_integer("1"), Ok(1));
+		assert_eq!(parse_positive_integer("25"), Ok(25));
+		assert!(parse_positive_integer("0").is_err());
+		assert!(parse_positive_integer("-1").is_err());
+		assert!(parse_positive_integer("a").is_err());
+	}
+
+	#[test]
+	fn parses_aspect_ratios() {
+		assert_eq!(parse_aspect_ratio("16:9"), Ok(16.0 / 9.0));
+		assert_eq!(parse_aspect_ratio("1.5"), Ok(1.5));
+		assert_eq!(parse_aspect_ratio("1:2"), Ok(0.5));
+		for aspect_ratio in ["0", "-1", "1:0", "0:1", "16x9", "a:b", "inf", ""] {
+			assert!(parse_aspect_ratio(aspect_ratio).is_err(), "{aspect_ratio:?}");
+		}
+	}
+
+	#[test]
+	fn parses_spacing() {
+		assert_eq!(parse_spacing("8"), Ok((8, 8)));
+		assert_eq!(parse_spacing("8x4"), Ok((8, 4)));
+		assert_eq!(parse_spacing("0"), Ok((0, 0)));
+		assert!(parse_spacing("8x4x2").is_err());
+		assert!(parse_spacing("-8").is_err());
+		assert!(parse_spacing("8px").is_err());
+	}
+
+	#[test]
+	fn parses_colors() {
+		assert_eq!(parse_color("#ff8800"), Ok(Rgba([255, 136, 0, 255])));
+		assert_eq!(parse_color("FF8800"), Ok(Rgba([255, 136, 0, 255])));
+		assert_eq!(parse_color("#f80"), Ok(Rgba([255, 136, 0, 255])));
+		assert_eq!(parse_color("#ff880080"), Ok(Rgba([255, 136, 0, 128])));
+		assert_eq!(parse_color("#f808"), Ok(Rgba([255, 136, 0, 136])));
+		assert_eq!(parse_color("White"), Ok(Rgba([255, 255, 255, 255])));
+		assert_eq!(parse_color("transparent"), Ok(Rgba([0, 0, 0, 0])));
+	}
+
+	#[test]
+	fn rejects_invalid_colors() {
+		for color in ["", "#", "#ff88f", "#gg0000", "#+f+f00", "#ff88001", "\u{e9}\u{e9}\u{e9}", "orange"] {
+			assert!(parse_color(color).is_err(), "{color:?}");
+		}
+	}
+
+	#[test]
+	fn parses_rects() {
+		assert_eq!(parse_rect("10,20,300,400"), Ok((10, 20, 300, 400)));
+		assert!(parse_rect("10,20,300").is_err());
+		assert!(parse_rect("-10,20,300,400").is_err());
+	}
+
+	#[test]
+	fn parses_lengths_with_units() {
+		assert_eq!(parse_length("48px"), Ok(Length::Pixels(48.0)));
+		assert_eq!(parse_length("0.5px"), Ok(Length::Pixels(0.5)));
+		assert_eq!(parse_length("10%"), Ok(Length::Fraction(0.1)));
+		assert_eq!(parse_length("150%"), Ok(Length::Fraction(1.5)));
+	}
+
+	#[test]
+	fn rejects_lengths_without_units() {
+		assert!(parse_length("1").is_err());
+		assert!(parse_length("0.99").is_err());
+		assert!(parse_length("48").is_err());
+		assert!(parse_length("px").is_err());
+		assert!(parse_length("-10%").is_err());
+	}
+
+	#[test]
+	fn parses_angles() {
+		assert_eq!(parse_angle("30"), Ok(30.0));
+		assert_eq!(parse_angle("0"), Ok(0.0));
+		assert_eq!(parse_angle("12.5"), Ok(12.5));
+		for angle in ["-1", "NaN", "inf", "30deg", ""] {
+			assert!(parse_angle(angle).is_err(), "{angle:?}");
+		}
+	}
+
+	fn args(args: &[&str]) -> Vec<OsString> {
+		args.iter().map(OsString::from).collect()
+	}
+
+	fn with_run(list: &[&str]) -> Vec<OsString> {
+		with_default_command(args(list), &["detect", "run"], &["--jobs"], "run")
+	}
+
+	#[test]
+	fn inserts_the_default_command() {
+		assert_eq!(with_run(&["face-grid"]), args(&["face-grid", "run"]));
+		assert_eq!(with_run(&["face-grid", "--input", "a"]), args(&["face-grid", "run", "--input", "a"]));
+		assert_eq!(
+			with_run(&["face-grid", "--jobs", "2", "--input", "a"]),
+			args(&["face-grid", "--jobs", "2", "run", "--input", "a"])
+		);
+		assert_eq!(with_run(&["face-grid", "--jobs=2"]), args(&["face-grid", "--jobs=2", "run"]));
+	}
+
+	#[test]
+	fn keeps_explicit_commands() {
+		for list in [
+			&["face-grid", "detect", "--input", "a"][..],
+			&["face-grid", "--jobs", "2", "run"],
+			&["face-grid", "--help"],
+			&["face-grid", "-V"],
+		] {
+			assert_eq!(with_run(list), args(list));
+		}
+	}
+}
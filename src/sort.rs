@@ -0,0 +1,248 @@
+use std::cmp::{Ordering, Reverse};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::RgbaImage;
+use strum_macros::{Display, EnumString, VariantNames};
+
+use crate::exif;
+use crate::grid::Cell;
+
+/// Order in which cells are placed in the grid.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Display, VariantNames)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum SortOrder {
+	/// By file path.
+	Name,
+	/// By file modification time.
+	Mtime,
+	/// By the date the photo was taken, from its EXIF data. Images without a date sort after all dates (or before, if reversed).
+	ExifDate,
+	/// By face detection confidence.
+	Confidence,
+	/// By face area in the source image.
+	FaceSize,
+	/// By the average brightness of the cell.
+	Brightness,
+	/// By the hue of the average color of the cell.
+	Hue,
+	/// In random order, repeatable with the same seed.
+	Random,
+}
+
+/// Wraps a float so it can be used as a sort key.
+#[derive(PartialEq)]
+struct FloatKey(f32);
+
+impl Eq for FloatKey {}
+
+impl PartialOrd for FloatKey {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for FloatKey {
+	fn cmp(&self, other: &Self) -> Ordering {
+		self.0.total_cmp(&other.0)
+	}
+}
+
+/// Small pseudorandom number generator (SplitMix64), so random orders can be repeated from a seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+	fn next(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+}
+
+/**
+ * Create a new random seed, from the system's randomness if available, or else the current time
+ */
+pub fn random_seed() -> u64 {
+	getrandom::u64().unwrap_or_else(|_| {
+		SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as u64)
+	})
+}
+
+/**
+ * Shuffle cells (Fisher-Yates); the same seed always gives the same order
+ */
+fn shuffle(cells: &mut [Cell], seed: u64) {
+	let mut random = SplitMix64(seed);
+	for i in (1..cells.len()).rev() {
+		let j = (random.next() % (i as u64 + 1)) as usize;
+		cells.swap(i, j);
+	}
+}
+
+/**
+ * Sort cells by a key, in ascending order (or descending, if reversed). Either way, cells with the same key keep
+ * their order.
+ */
+fn sort_by_key<K: Ord>(cells: &mut [Cell], reverse: bool, key: impl Fn(&Cell) -> K) {
+	if reverse {
+		cells.sort_by_cached_key(|cell| Reverse(key(cell)));
+	} else {
+		cells.sort_by_cached_key(key);
+	}
+}
+
+/**
+ * Sort cells in ascending order (or descending, if reversed). Cells with the same key keep their order, even
+ * when reversed. The seed is used for the random order, which is simply reversed.
+ */
+pub fn sort_cells(cells: &mut [Cell], order: SortOrder, reverse: bool, seed: u64) {
+	match order {
+		SortOrder::Name => sort_by_key(cells, reverse, |cell| cell.path.clone()),
+		SortOrder::Mtime => sort_by_key(cells, reverse, |cell| {
+			fs::metadata(&cell.path).and_then(|metadata| metadata.modified()).ok()
+		}),
+		SortOrder::ExifDate => sort_by_key(cells, reverse, |cell| {
+			let date = exif::read_date(&cell.path);
+			(date.is_none(), date)
+		}),
+		SortOrder::Confidence => sort_by_key(cells, reverse, |cell| FloatKey(cell.face.confidence)),
+		SortOrder::FaceSize => {
+			sort_by_key(cells, reverse, |cell| FloatKey(cell.face.rect.2 * cell.face.rect.3))
+		}
+		SortOrder::Brightness => sort_by_key(cells, reverse, |cell| FloatKey(brightness(&cell.image))),
+		SortOrder::Hue => sort_by_key(cells, reverse, |cell| FloatKey(hue(&cell.image))),
+		SortOrder::Random => {
+			shuffle(cells, seed);
+			if reverse {
+				cells.reverse();
+			}
+		}
+	}
+}
+
+/**
+ * Find the average color of the visible pixels of an image, as RGB from 0 to 1
+ */
+fn average_color(image: &RgbaImage) -> [f32; 3] {
+	let mut sum = [0f32; 3];
+	let mut count = 0;
+	for pixel in image.pixels().filter(|pixel| pixel.0[3] > 0) {
+		for (value, channel) in sum.iter_mut().zip(pixel.0) {
+			*value += channel as f32 / 255.0;
+		}
+		count += 1;
+	}
+	sum.map(|value| value / count.max(1) as f32)
+}
+
+/**
+ * Find the average brightness (luma) of the visible pixels of an image, from 0 to 1
+ */
+fn brightness(image: &RgbaImage) -> f32 {
+	let [r, g, b] = average_color(image);
+	0.299 * r + 0.587 * g + 0.114 * b
+}
+
+/**
+ * Find the hue of the average color of the visible pixels of an image, in degrees; grays have a hue of 0
+ */
+fn hue(image: &RgbaImage) -> f32 {
+	let [r, g, b] = average_color(image);
+	let max = r.max(g).max(b);
+	let chroma = max - r.min(g).min(b);
+	if chroma == 0.0 {
+		return 0.0;
+	}
+	let hue = if max == r {
+		((g - b) / chroma).rem_euclid(6.0)
+	} else if max == g {
+		(b - r) / chroma + 2.0
+	} else {
+		(r - g) / chroma + 4.0
+	};
+	hue * 60.0
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use std::path::PathBuf;
+
+	use super::*;
+	use crate::align::FaceTransform;
+	use crate::faces::Face;
+
+	/// A cell with a path and a face confidence, as they're the keys sorted by.
+	pub(crate) fn cell(path: &str, confidence: f32) -> Cell {
+		Cell {
+			path: PathBuf::from(path),
+			face: Face {
+				rect: (0.0, 0.0, 10.0, 10.0),
+				landmarks: vec![],
+				confidence,
+			},
+			transform: FaceTransform {
+				scale: 1.0,
+				source_anchor: (5.0, 5.0),
+				cell_anchor: (5.0, 5.0),
+				angle: 0.0,
+			},
+			image: RgbaImage::new(1, 1),
+			offset: (0, 0),
+		}
+	}
+
+	pub(crate) fn paths(cells: &[Cell]) -> Vec<&str> {
+		cells.iter().map(|cell| cell.path.to_str().unwrap()).collect()
+	}
+
+	#[test]
+	fn sorts_by_key() {
+		let mut cells = vec![cell("c.jpg", 0.5), cell("a.jpg", 0.9), cell("b.jpg", 0.7)];
+		sort_cells(&mut cells, SortOrder::Name, false, 0);
+		assert_eq!(paths(&cells), ["a.jpg", "b.jpg", "c.jpg"]);
+		sort_cells(&mut cells, SortOrder::Confidence, false, 0);
+		assert_eq!(paths(&cells), ["c.jpg", "b.jpg", "a.jpg"]);
+		sort_cells(&mut cells, SortOrder::Confidence, true, 0);
+		assert_eq!(paths(&cells), ["a.jpg", "b.jpg", "c.jpg"]);
+	}
+
+	#[test]
+	fn cells_with_the_same_key_keep_their_order() {
+		let cells = || vec![cell("a.jpg", 0.5), cell("b.jpg", 0.9), cell("c.jpg", 0.5), cell("d.jpg", 0.9)];
+		let mut ascending = cells();
+		sort_cells(&mut ascending, SortOrder::Confidence, false, 0);
+		assert_eq!(paths(&ascending), ["a.jpg", "c.jpg", "b.jpg", "d.jpg"]);
+		let mut descending = cells();
+		sort_cells(&mut descending, SortOrder::Confidence, true, 0);
+		assert_eq!(paths(&descending), ["b.jpg", "d.jpg", "a.jpg", "c.jpg"]);
+	}
+
+	#[test]
+	fn shuffles_are_repeatable_with_the_same_seed() {
+		let cells = || (0..20).map(|index| cell(&format!("{}.jpg", index), 1.0)).collect::<Vec<Cell>>();
+		let shuffled = |seed| {
+			let mut cells = cells();
+			sort_cells(&mut cells, SortOrder::Random, false, seed);
+			paths(&cells).into_iter().map(String::from).collect::<Vec<String>>()
+		};
+		assert_eq!(shuffled(42), shuffled(42));
+		assert_ne!(shuffled(42), shuffled(43));
+		assert_ne!(shuffled(42), paths(&cells()));
+
+		// The same order is reversed
+		let mut reversed = cells();
+		sort_cells(&mut reversed, SortOrder::Random, true, 42);
+		let mut expected = shuffled(42);
+		expected.reverse();
+		assert_eq!(paths(&reversed), expected);
+	}
+
+	#[test]
+	fn images_without_a_date_sort_last() {
+		let mut cells = vec![cell("missing-a.jpg", 1.0), cell("missing-b.jpg", 1.0)];
+		sort_cells(&mut cells, SortOrder::ExifDate, false, 0);
+		assert_eq!(paths(&cells), ["missing-a.jpg", "missing-b.jpg"]);
+	}
+}